@@ -1,46 +1,330 @@
 use alloc::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub mod bump;
+pub mod fixed_size_block;
+pub mod linked_list;
+pub mod tracking;
+
+pub use tracking::{assert_no_leaks, heap_stats, HeapStats, TrackingAllocator};
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;  // Virtual Address
-pub const HEAP_SIZE: usize = 100 * 1024;          // 100 KiB
+pub const HEAP_SIZE: usize = 100 * 1024;          // 100 KiB, mapped at boot
+/// Virtual memory set aside for the heap to grow into
+pub const HEAP_WINDOW_SIZE: usize = 1024 * 1024 * 1024;  // 1 GiB
+/// How far the heap may grow unless told otherwise
+pub const DEFAULT_HEAP_LIMIT: usize = 16 * 1024 * 1024;  // 16 MiB
+
+// The heap grows by at least this much at a time
+const GROW_STEP: usize = 64 * 1024;
+const PAGE_SIZE: usize = 4096;
 
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper,
-        Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, MapperAllSizes,
+        PageTableFlags, Size1GiB, Size2MiB, Size4KiB,
     },
     VirtAddr,
 };
+use crate::memory;
 
 // Map Heap Region to Physical Memory
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError> {
-    // Get all pages
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
+// Large heaps get huge pages if the frame allocator can provide them.
+pub fn init_heap<A>(
+    mapper: &mut impl MapperAllSizes,
+    frame_allocator: &mut A,
+) -> Result<(), MapToError>
+where
+    A: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB> + FrameAllocator<Size1GiB>,
+{
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | memory::no_execute();
+    memory::map_range(mapper, frame_allocator, VirtAddr::new(HEAP_START as u64),
+                      HEAP_SIZE as u64, flags)?;
+
+    unsafe {
+        super::ALLOCATOR.inner().lock().init(HEAP_START, HEAP_SIZE);
+    }
+
+    Ok(())
+}
+
+use crate::spinlock::IrqSpinLock;
+
+/// An allocator design the kernel heap can be built on. It manages the
+/// memory handed to it with `init`.
+pub trait HeapAllocator {
+    /// Unsafe because `start..start + size` must be mapped and unused
+    unsafe fn init(&mut self, start: usize, size: usize);
+
+    /// Returns null if there is no room
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+
+    /// Unsafe because `ptr` must come from `allocate` with the same layout
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+}
+
+/// The design behind the kernel heap, picked with one of the heap-* cargo
+/// features
+#[cfg(feature = "heap-bump")]
+pub type Backend = bump::BumpAllocator;
+#[cfg(feature = "heap-linked-list")]
+pub type Backend = linked_list::LinkedListAllocator;
+#[cfg(feature = "heap-fixed-size-block")]
+pub type Backend = fixed_size_block::FixedSizeBlockAllocator;
+
+#[cfg(not(any(feature = "heap-bump", feature = "heap-linked-list",
+              feature = "heap-fixed-size-block")))]
+compile_error!("enable one of the heap-bump, heap-linked-list and heap-fixed-size-block features");
+#[cfg(any(all(feature = "heap-bump", feature = "heap-linked-list"),
+          all(feature = "heap-bump", feature = "heap-fixed-size-block"),
+          all(feature = "heap-linked-list", feature = "heap-fixed-size-block")))]
+compile_error!("only one of the heap-* features can be enabled");
+
+/// The kernel heap. Starts out with the memory `init_heap` mapped, and
+/// maps more of the heap window in chunks when that runs out. Chunks at the
+/// end go back to the frame allocator once nothing in them is in use, but
+/// one empty chunk is kept for the next allocation.
+pub struct KernelHeap {
+    initial: Backend,
+    initial_end: usize,
+    // Newest chunk, each one links to the one before
+    last_chunk: *mut Chunk,
+    // Bytes of the window that are mapped
+    mapped: usize,
+    limit: usize,
+}
+
+// Header at the start of every chunk, the rest of the chunk is its heap
+struct Chunk {
+    heap: Backend,
+    size: usize,
+    used: usize,
+    previous: *mut Chunk,
+}
+
+// The chunks are only reachable through the heap
+unsafe impl Send for KernelHeap {}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+impl KernelHeap {
+    pub const fn empty() -> Self {
+        KernelHeap {
+            initial: Backend::new(),
+            initial_end: 0,
+            last_chunk: null_mut(),
+            mapped: 0,
+            limit: DEFAULT_HEAP_LIMIT,
+        }
+    }
+
+    /// Unsafe because `start..start + size` must be mapped and unused, and
+    /// the rest of the heap window must be free to map
+    pub unsafe fn init(&mut self, start: usize, size: usize) {
+        self.initial.init(start, size);
+        self.initial_end = start + size;
+        self.mapped = size;
+    }
+
+    /// Bytes currently mapped
+    pub fn size(&self) -> usize {
+        self.mapped
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Caps how much of the window may be mapped. Memory that is mapped
+    /// already stays.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.min(HEAP_WINDOW_SIZE);
+    }
+
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = self.initial.allocate(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+        let mut chunk = self.last_chunk;
+        while let Some(current) = unsafe { chunk.as_mut() } {
+            let ptr = current.heap.allocate(layout);
+            if !ptr.is_null() {
+                current.used += layout.size();
+                return ptr;
+            }
+            chunk = current.previous;
+        }
+
+        match unsafe { self.grow(layout).as_mut() } {
+            Some(chunk) => {
+                let ptr = chunk.heap.allocate(layout);
+                if !ptr.is_null() {
+                    chunk.used += layout.size();
+                }
+                ptr
+            }
+            None => null_mut(),
+        }
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let addr = ptr as usize;
+        if (HEAP_START..self.initial_end).contains(&addr) {
+            self.initial.deallocate(ptr, layout);
+            return;
+        }
+        let mut chunk = self.last_chunk;
+        while let Some(current) = chunk.as_mut() {
+            let start = chunk as usize;
+            if (start..start + current.size).contains(&addr) {
+                current.heap.deallocate(ptr, layout);
+                current.used -= layout.size();
+                self.shrink();
+                return;
+            }
+            chunk = current.previous;
+        }
+        panic!("deallocating {:p}, which is not in the heap", ptr);
+    }
+
+    // Maps a chunk that can hold `layout` at the end of the heap
+    fn grow(&mut self, layout: Layout) -> *mut Chunk {
+        let header = size_of::<Chunk>();
+        let size = align_up(header + layout.size() + layout.align(), PAGE_SIZE).max(GROW_STEP);
+        if self.mapped + size > self.limit {
+            return null_mut();
+        }
+        let start = HEAP_START + self.mapped;
+        if !map_pages(start, size) {
+            return null_mut();
+        }
+
+        let chunk = start as *mut Chunk;
+        unsafe {
+            chunk.write(Chunk {
+                heap: Backend::new(),
+                size,
+                used: 0,
+                previous: self.last_chunk,
+            });
+            (*chunk).heap.init(start + header, size - header);
+        }
+        self.last_chunk = chunk;
+        self.mapped += size;
+        chunk
+    }
+
+    // Unmaps the chunks at the end that are no longer used. One empty
+    // chunk of the usual size stays, so allocating and freeing in a loop
+    // doesn't map and unmap it every time.
+    fn shrink(&mut self) {
+        while let Some(chunk) = unsafe { self.last_chunk.as_ref() } {
+            if chunk.used != 0 {
+                break;
+            }
+            let previous_empty = unsafe { chunk.previous.as_ref() }
+                .map_or(false, |previous| previous.used == 0);
+            if chunk.size <= GROW_STEP && !previous_empty {
+                break;
+            }
+            let (start, size, previous) = (self.last_chunk as usize, chunk.size, chunk.previous);
+            if !unmap_pages(start, size) {
+                break;
+            }
+            self.last_chunk = previous;
+            self.mapped -= size;
+        }
+    }
+}
+
+// The heap may be called by code that holds MAPPER or FRAME_ALLOCATOR, so
+// waiting for them could dead lock. If they are taken the heap doesn't
+// grow or shrink this time, and an allocation that needed the heap to
+// grow fails.
+static SKIPPED_RESIZES: AtomicU64 = AtomicU64::new(0);
+
+/// Number of times the heap didn't grow or shrink because MAPPER or
+/// FRAME_ALLOCATOR was taken
+pub fn skipped_resizes() -> u64 {
+    SKIPPED_RESIZES.load(Ordering::Relaxed)
+}
+
+fn map_pages(start: usize, size: usize) -> bool {
+    // Empty slabs may still hold frames. Shrinking needs the frame
+    // allocator, so not while try_map_pages holds it.
+    try_map_pages(start, size) || (memory::slab::shrink_all() > 0 && try_map_pages(start, size))
+}
+
+fn try_map_pages(start: usize, size: usize) -> bool {
+    let (mut mapper, mut frames) = match (memory::MAPPER.try_lock(),
+                                          memory::FRAME_ALLOCATOR.try_lock()) {
+        (Some(mapper), Some(frames)) => (mapper, frames),
+        _ => {
+            SKIPPED_RESIZES.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+    };
+    let (mapper, frames) = match (mapper.as_mut(), frames.as_mut()) {
+        (Some(mapper), Some(frames)) => (mapper, frames),
+        _ => return false,
     };
+    let start = VirtAddr::new(start as u64);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | memory::no_execute();
+    if memory::map_range(mapper, frames, start, size as u64, flags).is_err() {
+        // Undo the part that was mapped
+        let _ = memory::unmap_range(mapper, frames, start, size as u64, true);
+        return false;
+    }
+    true
+}
 
-    // Map each page
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        // flush() refreshs TLB
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+fn unmap_pages(start: usize, size: usize) -> bool {
+    let (mut mapper, mut frames) = match (memory::MAPPER.try_lock(),
+                                          memory::FRAME_ALLOCATOR.try_lock()) {
+        (Some(mapper), Some(frames)) => (mapper, frames),
+        _ => {
+            SKIPPED_RESIZES.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+    };
+    match (mapper.as_mut(), frames.as_mut()) {
+        (Some(mapper), Some(frames)) => {
+            memory::unmap_range(mapper, frames, VirtAddr::new(start as u64), size as u64, true)
+                .expect("failed to unmap heap chunk");
+            true
+        }
+        _ => false,
     }
+}
 
-    unsafe {
-        super::ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+/// Returns the bytes in use and the total size of the heap
+pub fn heap_usage() -> (usize, usize) {
+    (heap_stats().in_use, super::ALLOCATOR.inner().lock().size())
+}
+
+/// How far the heap may grow
+pub fn heap_limit() -> usize {
+    super::ALLOCATOR.inner().lock().limit()
+}
+
+pub fn set_heap_limit(limit: usize) {
+    super::ALLOCATOR.inner().lock().set_limit(limit);
+}
+
+// Allocating from an interrupt handler must not dead lock on the heap
+unsafe impl GlobalAlloc for IrqSpinLock<KernelHeap> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout)
     }
 
-    Ok(())
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().deallocate(ptr, layout);
+    }
 }
 
 pub struct Dummy;
@@ -53,4 +337,4 @@ unsafe impl GlobalAlloc for Dummy {
     unsafe fn dealloc(&self, _prt: *mut u8, _layout: Layout) {
         panic!("dealloc should never be called")
     }
-}
\ No newline at end of file
+}
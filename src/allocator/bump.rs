@@ -0,0 +1,54 @@
+// Bump allocator
+//
+// Hands out memory by moving a pointer forward. Memory is only reused
+// once every allocation has been freed, which makes it fast and simple,
+// but unsuited to long running workloads.
+use alloc::alloc::Layout;
+use core::ptr::null_mut;
+
+use super::{align_up, HeapAllocator};
+
+pub struct BumpAllocator {
+    start: usize,
+    end: usize,
+    next: usize,
+    allocations: usize,
+}
+
+impl BumpAllocator {
+    pub const fn new() -> Self {
+        BumpAllocator {
+            start: 0,
+            end: 0,
+            next: 0,
+            allocations: 0,
+        }
+    }
+}
+
+impl HeapAllocator for BumpAllocator {
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        self.start = start;
+        self.end = start + size;
+        self.next = start;
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let start = align_up(self.next, layout.align());
+        match start.checked_add(layout.size()) {
+            Some(end) if end <= self.end => {
+                self.next = end;
+                self.allocations += 1;
+                start as *mut u8
+            }
+            _ => null_mut(),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, _ptr: *mut u8, _layout: Layout) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = self.start;
+        }
+    }
+}
@@ -0,0 +1,72 @@
+// Fixed size block allocator
+//
+// Small allocations are rounded up to one of a few block sizes. Each size
+// has a list of free blocks, so allocating and freeing is a push or pop.
+// Blocks come from a linked list allocator, which also serves everything
+// larger than the largest block. Freed blocks stay in their list.
+use alloc::alloc::Layout;
+use core::ptr::null_mut;
+
+use super::linked_list::LinkedListAllocator;
+use super::HeapAllocator;
+
+// Powers of two, so a block of a size is also aligned to it
+const BLOCK_SIZES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct BlockNode {
+    next: *mut BlockNode,
+}
+
+pub struct FixedSizeBlockAllocator {
+    list_heads: [*mut BlockNode; 9],
+    fallback: LinkedListAllocator,
+}
+
+// Index of the smallest block that can hold `layout`
+fn list_index(layout: Layout) -> Option<usize> {
+    let required = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&size| size >= required)
+}
+
+impl FixedSizeBlockAllocator {
+    pub const fn new() -> Self {
+        FixedSizeBlockAllocator {
+            list_heads: [null_mut(); 9],
+            fallback: LinkedListAllocator::new(),
+        }
+    }
+}
+
+impl HeapAllocator for FixedSizeBlockAllocator {
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        self.fallback.init(start, size);
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match list_index(layout) {
+            Some(index) => match unsafe { self.list_heads[index].as_mut() } {
+                Some(block) => {
+                    self.list_heads[index] = block.next;
+                    block as *mut BlockNode as *mut u8
+                }
+                None => {
+                    let size = BLOCK_SIZES[index];
+                    let layout = Layout::from_size_align(size, size).unwrap();
+                    self.fallback.allocate(layout)
+                }
+            },
+            None => self.fallback.allocate(layout),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(layout) {
+            Some(index) => {
+                let block = ptr as *mut BlockNode;
+                block.write(BlockNode { next: self.list_heads[index] });
+                self.list_heads[index] = block;
+            }
+            None => self.fallback.deallocate(ptr, layout),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// Linked list allocator
+//
+// Free regions are kept in a list sorted by address, linked through the
+// regions themselves. Allocation takes the first region that fits and
+// gives back what is left on either side. Freed regions are merged with
+// free neighbours, so the heap doesn't fragment into ever smaller pieces.
+use alloc::alloc::Layout;
+use core::mem::{align_of, size_of};
+use core::ptr::null_mut;
+
+use super::{align_up, HeapAllocator};
+
+struct ListNode {
+    size: usize,
+    next: *mut ListNode,
+}
+
+// Every free region must be able to hold a node
+const NODE_SIZE: usize = size_of::<ListNode>();
+const NODE_ALIGN: usize = align_of::<ListNode>();
+
+pub struct LinkedListAllocator {
+    // Not a region, only points at the first one
+    head: ListNode,
+}
+
+impl LinkedListAllocator {
+    pub const fn new() -> Self {
+        LinkedListAllocator {
+            head: ListNode {
+                size: 0,
+                next: null_mut(),
+            },
+        }
+    }
+
+    /// Number of free regions and the bytes in them
+    pub fn free_regions(&self) -> (usize, usize) {
+        let (mut count, mut bytes) = (0, 0);
+        let mut region = self.head.next;
+        while let Some(current) = unsafe { region.as_ref() } {
+            count += 1;
+            bytes += current.size;
+            region = current.next;
+        }
+        (count, bytes)
+    }
+
+    // Allocations are made large and aligned enough to become a free
+    // region again
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let size = align_up(layout.size().max(NODE_SIZE), NODE_ALIGN);
+        (size, layout.align().max(NODE_ALIGN))
+    }
+
+    // Where an allocation goes in a region, if it fits. Whatever is left
+    // on either side must be large enough for a node.
+    fn fit(region_start: usize, region_end: usize, size: usize, align: usize)
+        -> Option<usize> {
+        let mut start = align_up(region_start, align);
+        if start != region_start && start - region_start < NODE_SIZE {
+            start = align_up(region_start + NODE_SIZE, align);
+        }
+        let end = start.checked_add(size)?;
+        if end > region_end {
+            return None;
+        }
+        let rest = region_end - end;
+        if rest != 0 && rest < NODE_SIZE {
+            return None;
+        }
+        Some(start)
+    }
+
+    // Inserts a free region in address order, merging it with its
+    // neighbours if they touch
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        debug_assert!(addr % NODE_ALIGN == 0 && size >= NODE_SIZE);
+        let head: *mut ListNode = &mut self.head;
+        let mut previous = head;
+        while let Some(next) = (*previous).next.as_mut() {
+            if next as *mut ListNode as usize > addr {
+                break;
+            }
+            previous = next;
+        }
+
+        let next = (*previous).next;
+        let node = addr as *mut ListNode;
+        node.write(ListNode { size, next });
+        (*previous).next = node;
+
+        if !next.is_null() && addr + size == next as usize {
+            (*node).size += (*next).size;
+            (*node).next = (*next).next;
+        }
+        if previous != head && previous as usize + (*previous).size == addr {
+            (*previous).size += (*node).size;
+            (*previous).next = (*node).next;
+        }
+    }
+}
+
+impl HeapAllocator for LinkedListAllocator {
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        let aligned = align_up(start, NODE_ALIGN);
+        let end = (start + size) & !(NODE_ALIGN - 1);
+        if end >= aligned + NODE_SIZE {
+            self.add_free_region(aligned, end - aligned);
+        }
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
+        let mut previous: *mut ListNode = &mut self.head;
+        unsafe {
+            while let Some(region) = (*previous).next.as_mut() {
+                let region_start = region as *mut ListNode as usize;
+                let region_end = region_start + region.size;
+                if let Some(start) = Self::fit(region_start, region_end, size, align) {
+                    (*previous).next = region.next;
+                    if start > region_start {
+                        self.add_free_region(region_start, start - region_start);
+                    }
+                    if start + size < region_end {
+                        self.add_free_region(start + size, region_end - start - size);
+                    }
+                    return start as *mut u8;
+                }
+                previous = region;
+            }
+        }
+        null_mut()
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr as usize, size);
+    }
+}
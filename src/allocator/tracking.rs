@@ -0,0 +1,290 @@
+// Heap statistics and leak tracking
+//
+// Wraps the global allocator and counts what goes through it. In leak
+// tracking mode every allocation is also recorded together with the return
+// addresses of its callers, until it is freed again. The records live in a
+// fixed table, recording them must not allocate.
+use alloc::alloc::{GlobalAlloc, Layout};
+use x86_64::VirtAddr;
+
+use crate::spinlock::IrqSpinLock;
+use crate::serial_println;
+
+/// Allocation sizes are counted in buckets of up to 8, 16, 32, ... bytes,
+/// the last one takes everything larger
+pub const HISTOGRAM_BUCKETS: usize = 12;
+/// Return addresses recorded per allocation, innermost first
+pub const CALLER_DEPTH: usize = 6;
+// Live allocations leak tracking can hold at a time
+const MAX_TRACKED: usize = 512;
+// Larger than any kernel stack, bounds the stack walk
+const MAX_STACK_SIZE: usize = 1024 * 1024;
+
+/// Upper end of a histogram bucket, None for the last one
+pub fn bucket_limit(bucket: usize) -> Option<usize> {
+    if bucket + 1 < HISTOGRAM_BUCKETS {
+        Some(8 << bucket)
+    } else {
+        None
+    }
+}
+
+fn bucket(size: usize) -> usize {
+    (0..HISTOGRAM_BUCKETS - 1)
+        .find(|&bucket| size <= 8 << bucket)
+        .unwrap_or(HISTOGRAM_BUCKETS - 1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes handed out and not yet freed
+    pub in_use: usize,
+    pub peak: usize,
+    pub allocations: u64,
+    pub frees: u64,
+    /// Allocations the heap couldn't satisfy
+    pub failures: u64,
+    /// Allocations by size
+    pub histogram: [u64; HISTOGRAM_BUCKETS],
+}
+
+impl HeapStats {
+    pub fn live(&self) -> u64 {
+        self.allocations - self.frees
+    }
+}
+
+/// An allocation made while leak tracking was on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+    pub ptr: usize,
+    pub size: usize,
+    /// Return addresses, 0 where the stack walk stopped
+    pub callers: [usize; CALLER_DEPTH],
+}
+
+/// What leak tracking found still allocated when it was stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeakSummary {
+    pub count: usize,
+    pub bytes: usize,
+    /// Allocations that didn't fit in the table. Their leaks go unnoticed.
+    pub untracked: usize,
+}
+
+struct Tracker {
+    stats: HeapStats,
+    tracking: bool,
+    live: [Allocation; MAX_TRACKED],
+    len: usize,
+    untracked: usize,
+}
+
+impl Tracker {
+    fn record(&mut self, ptr: usize, size: usize) {
+        if self.len == MAX_TRACKED {
+            self.untracked += 1;
+            return;
+        }
+        self.live[self.len] = Allocation { ptr, size, callers: callers() };
+        self.len += 1;
+    }
+
+    fn forget(&mut self, ptr: usize) {
+        if let Some(index) = self.live[..self.len].iter().position(|live| live.ptr == ptr) {
+            self.len -= 1;
+            self.live[index] = self.live[self.len];
+        }
+    }
+}
+
+// The stack a walk started on: the pages from RSP up to the first one that
+// isn't mapped, usually the guard page of the next stack
+struct StackBounds {
+    low: usize,
+    // Pages below this are known to be mapped
+    mapped_end: usize,
+}
+
+impl StackBounds {
+    fn contains(&mut self, addr: usize, size: usize) -> bool {
+        if addr < self.low || addr - self.low + size > MAX_STACK_SIZE {
+            return false;
+        }
+        while self.mapped_end < addr + size {
+            if crate::memory::translate(VirtAddr::new(self.mapped_end as u64)).is_none() {
+                return false;
+            }
+            self.mapped_end += 4096;
+        }
+        true
+    }
+}
+
+// Walks the frame pointer chain. The kernel keeps frame pointers, see the
+// target spec. Stops at the first frame outside the current stack.
+#[inline(always)]
+fn callers() -> [usize; CALLER_DEPTH] {
+    let mut callers = [0; CALLER_DEPTH];
+    let (mut frame, rsp): (usize, usize);
+    unsafe {
+        asm!("mov %rbp, $0" : "=r"(frame));
+        asm!("mov %rsp, $0" : "=r"(rsp));
+    }
+    let mut stack = StackBounds { low: rsp, mapped_end: rsp & !0xfff };
+    for caller in callers.iter_mut() {
+        if frame % 8 != 0 || !stack.contains(frame, 16) {
+            break;
+        }
+        let (next, ret) = unsafe { (*(frame as *const usize), *((frame + 8) as *const usize)) };
+        *caller = ret;
+        // The stack grows down, callers have their frames above ours
+        if next <= frame {
+            break;
+        }
+        frame = next;
+    }
+    callers
+}
+
+/// Global allocator that keeps `HeapStats` for the allocator it wraps
+pub struct TrackingAllocator<A> {
+    inner: A,
+    tracker: IrqSpinLock<Tracker>,
+}
+
+impl<A> TrackingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        TrackingAllocator {
+            inner,
+            tracker: IrqSpinLock::new("heap_stats", Tracker {
+                stats: HeapStats {
+                    in_use: 0,
+                    peak: 0,
+                    allocations: 0,
+                    frees: 0,
+                    failures: 0,
+                    histogram: [0; HISTOGRAM_BUCKETS],
+                },
+                tracking: false,
+                live: [Allocation { ptr: 0, size: 0, callers: [0; CALLER_DEPTH] }; MAX_TRACKED],
+                len: 0,
+                untracked: 0,
+            }),
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.tracker.lock().stats
+    }
+
+    /// Records every allocation from now on until it is freed. Forgets
+    /// what an earlier run found.
+    pub fn start_leak_tracking(&self) {
+        let mut tracker = self.tracker.lock();
+        tracker.tracking = true;
+        tracker.len = 0;
+        tracker.untracked = 0;
+    }
+
+    /// Stops recording. The allocations still recorded are the leaks.
+    pub fn stop_leak_tracking(&self) -> LeakSummary {
+        let mut tracker = self.tracker.lock();
+        tracker.tracking = false;
+        LeakSummary {
+            count: tracker.len,
+            bytes: tracker.live[..tracker.len].iter().map(|live| live.size).sum(),
+            untracked: tracker.untracked,
+        }
+    }
+
+    /// Calls `f` for each leak the last tracking run found
+    pub fn for_each_leak(&self, mut f: impl FnMut(&Allocation)) {
+        let mut index = 0;
+        loop {
+            // Not holding the lock while `f` runs, it may allocate
+            let leak = {
+                let tracker = self.tracker.lock();
+                if tracker.tracking || index >= tracker.len {
+                    return;
+                }
+                tracker.live[index]
+            };
+            f(&leak);
+            index += 1;
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        let mut tracker = self.tracker.lock();
+        if ptr.is_null() {
+            tracker.stats.failures += 1;
+            return ptr;
+        }
+        let stats = &mut tracker.stats;
+        stats.in_use += layout.size();
+        stats.peak = stats.peak.max(stats.in_use);
+        stats.allocations += 1;
+        stats.histogram[bucket(layout.size())] += 1;
+        if tracker.tracking {
+            tracker.record(ptr as usize, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        {
+            let mut tracker = self.tracker.lock();
+            tracker.stats.in_use -= layout.size();
+            tracker.stats.frees += 1;
+            if tracker.tracking {
+                tracker.forget(ptr as usize);
+            }
+        }
+        self.inner.dealloc(ptr, layout);
+    }
+}
+
+pub fn heap_stats() -> HeapStats {
+    crate::ALLOCATOR.stats()
+}
+
+pub fn start_leak_tracking() {
+    crate::ALLOCATOR.start_leak_tracking();
+}
+
+pub fn stop_leak_tracking() -> LeakSummary {
+    crate::ALLOCATOR.stop_leak_tracking()
+}
+
+pub fn for_each_leak(f: impl FnMut(&Allocation)) {
+    crate::ALLOCATOR.for_each_leak(f);
+}
+
+/// Runs `f` and panics if it leaves anything allocated, after printing
+/// what was leaked to serial. Also panics if `f` had more allocations live
+/// than could be tracked, since their leaks would go unnoticed.
+pub fn assert_no_leaks<R>(f: impl FnOnce() -> R) -> R {
+    start_leak_tracking();
+    let result = f();
+    let summary = stop_leak_tracking();
+    if summary.count != 0 {
+        for_each_leak(|leak| {
+            serial_println!("leaked {} bytes at {:#x}, callers {:x?}",
+                            leak.size, leak.ptr, leak.callers);
+        });
+        panic!("{} allocations ({} bytes) leaked", summary.count, summary.bytes);
+    }
+    if summary.untracked != 0 {
+        panic!("{} allocations couldn't be tracked, more than {} were live",
+               summary.untracked, MAX_TRACKED);
+    }
+    result
+}
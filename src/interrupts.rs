@@ -1,9 +1,12 @@
 // Interrupt Descriptor Table
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::idt::{
+    HandlerFunc, InterruptDescriptorTable, InterruptStackFrame,
+};
 
 use crate::println;
 use crate::gdt;
 use crate::hlt_loop;
+use crate::work_queue;
 
 use lazy_static::lazy_static;
 
@@ -20,12 +23,13 @@ lazy_static! {
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
 
-        // Hardware interrupts handlers
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
+        // Hardware interrupts go through the generic stubs, which call
+        // whatever handlers are registered for the line at runtime.
+        for (irq, &stub) in IRQ_STUBS.iter().enumerate() {
+            idt[usize::from(PIC_1_OFFSET) + irq].set_handler_fn(stub);
+        }
 
         idt
     };
@@ -47,6 +51,12 @@ extern "x86-interrupt" fn double_fault_handler(
     hlt_loop();
 }
 
+// Nothing in the system raises NMIs on its own. If one arrives (e.g. from
+// the QEMU monitor's `nmi` command), treat it as a request to report a hang.
+extern "x86-interrupt" fn nmi_handler(stack_frame: &mut InterruptStackFrame) {
+    crate::watchdog::fire(stack_frame, "non-maskable interrupt");
+}
+
 use x86_64::structures::idt::PageFaultErrorCode;
 
 extern "x86-interrupt" fn page_fault_handler(
@@ -62,80 +72,364 @@ extern "x86-interrupt" fn page_fault_handler(
 }
 
 use pic8259_simple::ChainedPics;
-use spin;
+use crate::spinlock::IrqSpinLock;
 
 // Primary PIC
 pub const PIC_1_OFFSET: u8 = 32;
 // Secondary PIC
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe {
+pub static PICS: IrqSpinLock<ChainedPics> =
+    IrqSpinLock::new("pics", unsafe {
         ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
     });
 
+// Number of interrupt lines served by the chained PICs
+pub const IRQ_COUNT: usize = 16;
+// Number of handlers that can share one interrupt line
+pub const MAX_SHARED_HANDLERS: usize = 4;
+
+// The line the secondary PIC is cascaded on. It must never be masked.
+const CASCADE_IRQ: u8 = 2;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Com1 = PIC_1_OFFSET + 4,
+    Mouse = PIC_2_OFFSET + 4,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
+    pub fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    /// The PIC line this interrupt arrives on
+    pub fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+}
+
+/// Handler for a hardware interrupt line.
+///
+/// Handlers run with interrupts disabled and must not send an EOI
+/// themselves: the dispatcher does that once every handler sharing the
+/// line has run.
+pub type IrqHandler = fn(&mut InterruptStackFrame);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidIrq(u8),
+    TooManyHandlers(u8),
+    NotRegistered(u8),
+}
+
+static IRQ_HANDLERS:
+    IrqSpinLock<[[Option<IrqHandler>; MAX_SHARED_HANDLERS]; IRQ_COUNT]> =
+    IrqSpinLock::new("irq_handlers", [[None; MAX_SHARED_HANDLERS]; IRQ_COUNT]);
+
+/// Adds `handler` to the handlers of `irq` and unmasks the line.
+///
+/// Several handlers may share one line; they are called in registration
+/// order.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if usize::from(irq) >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+
+    let mut handlers = IRQ_HANDLERS.lock();
+    let slot = handlers[usize::from(irq)]
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(IrqError::TooManyHandlers(irq))?;
+    *slot = Some(handler);
+    unmask_irq(irq);
+    Ok(())
+}
+
+/// Removes `handler` from the handlers of `irq`. The line is masked again
+/// once its last handler is gone.
+pub fn unregister_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if usize::from(irq) >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+
+    let mut handlers = IRQ_HANDLERS.lock();
+    let slots = &mut handlers[usize::from(irq)];
+    let slot = slots
+        .iter_mut()
+        .find(|slot| slot.map(|h| h as usize) == Some(handler as usize))
+        .ok_or(IrqError::NotRegistered(irq))?;
+    *slot = None;
+    if slots.iter().all(|slot| slot.is_none()) {
+        mask_irq(irq);
+    }
+    Ok(())
 }
 
-use crate::print;
+// Called by the stub of every hardware interrupt vector
+fn dispatch_irq(irq: u8, stack_frame: &mut InterruptStackFrame) {
+    let start = timestamp();
+
+    if is_spurious(irq) {
+        // The PIC raised the line but no interrupt is in service. Only
+        // the primary PIC has seen an interrupt if the secondary one sent
+        // it (on the cascade line), so only the primary gets an EOI.
+        if irq >= 8 {
+            let _pics = PICS.lock();
+            let mut port: Port<u8> = Port::new(PIC_1_COMMAND);
+            unsafe { port.write(PIC_EOI) };
+        }
+        IRQ_STATS.lock()[usize::from(irq)].spurious += 1;
+        return;
+    }
 
-extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: &mut InterruptStackFrame) {
-    print!(".");
+    // Copy the handlers out so that a handler may (un)register handlers
+    // without dead locking on IRQ_HANDLERS.
+    let handlers = IRQ_HANDLERS.lock()[usize::from(irq)];
+    for handler in handlers.iter().flatten() {
+        (*handler)(stack_frame);
+    }
 
     // We need to send an explicit "end of interrupt" (EOI) signal
     // so that the system knows we are ready to handle the next interrupt
     unsafe {
-        // Send EOI using ports
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+        PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq);
     }
+
+    let end = timestamp();
+    {
+        let mut stats = IRQ_STATS.lock();
+        let stats = &mut stats[usize::from(irq)];
+        stats.count += 1;
+        stats.last_timestamp = start;
+        stats.max_duration = stats.max_duration.max(end - start);
+    }
+
+    // Run the work the handlers deferred. The line is acknowledged, so
+    // interrupts can be enabled again while it runs.
+    if work_queue::has_pending_work() {
+        x86_64::instructions::interrupts::enable();
+        work_queue::run_pending_work();
+        x86_64::instructions::interrupts::disable();
+    }
+}
+
+// Generates one entry stub per interrupt line. The x86-interrupt calling
+// convention doesn't tell the handler which vector fired, so each stub
+// passes its own line number on to the dispatcher.
+macro_rules! irq_stubs {
+    ($($irq:expr => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(
+                stack_frame: &mut InterruptStackFrame) {
+                dispatch_irq($irq, stack_frame);
+            }
+        )*
+
+        // Entry stubs, indexed by interrupt line
+        static IRQ_STUBS: [HandlerFunc; IRQ_COUNT] = [$($name),*];
+    };
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: &mut InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
-    use pc_keyboard::{Keyboard, ScancodeSet1, DecodedKey, layouts};
-    use spin::Mutex;
+irq_stubs! {
+    0 => irq_stub_0, 1 => irq_stub_1, 2 => irq_stub_2, 3 => irq_stub_3,
+    4 => irq_stub_4, 5 => irq_stub_5, 6 => irq_stub_6, 7 => irq_stub_7,
+    8 => irq_stub_8, 9 => irq_stub_9, 10 => irq_stub_10, 11 => irq_stub_11,
+    12 => irq_stub_12, 13 => irq_stub_13, 14 => irq_stub_14,
+    15 => irq_stub_15,
+}
+
+use x86_64::instructions::port::Port;
+
+// Data ports of the primary and secondary PIC. Writing to them after the
+// initialization sets the interrupt mask register.
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_DATA: u16 = 0xa1;
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
+fn set_irq_masked(irq: u8, masked: bool) {
+    let (port, bit) = if irq < 8 {
+        (PIC_1_DATA, irq)
+    } else {
+        (PIC_2_DATA, irq - 8)
+    };
+
+    // Hold the PICS lock so that we don't interleave with an EOI
+    let _pics = PICS.lock();
+    let mut port: Port<u8> = Port::new(port);
+    unsafe {
+        let mask = port.read();
+        if masked {
+            port.write(mask | (1 << bit));
+        } else {
+            port.write(mask & !(1 << bit));
+        }
     }
+}
 
-    // We need to read the scancode, otherwise, new keyboard events can't
-    // be handled.
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
+/// Stops the PIC from delivering interrupts on `irq`
+pub fn mask_irq(irq: u8) {
+    set_irq_masked(irq, true);
+}
 
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
+/// Lets the PIC deliver interrupts on `irq` again
+pub fn unmask_irq(irq: u8) {
+    set_irq_masked(irq, false);
+}
+
+// Command ports of the primary and secondary PIC
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+
+// Operation Command Word 3 asking the PIC to return the In-Service Register
+// on the next read of the command port.
+const PIC_READ_ISR: u8 = 0x0b;
+// Non-specific end of interrupt
+const PIC_EOI: u8 = 0x20;
+
+// Returns the In-Service Registers of both PICs, secondary in the high byte
+fn read_isr() -> u16 {
+    let _pics = PICS.lock();
+    let mut command_1: Port<u8> = Port::new(PIC_1_COMMAND);
+    let mut command_2: Port<u8> = Port::new(PIC_2_COMMAND);
+    unsafe {
+        command_1.write(PIC_READ_ISR);
+        command_2.write(PIC_READ_ISR);
+        (u16::from(command_2.read()) << 8) | u16::from(command_1.read())
+    }
+}
+
+// IRQ 7 and IRQ 15 are what the PICs raise when an interrupt goes away
+// before it is acknowledged. A real interrupt has its ISR bit set.
+fn is_spurious(irq: u8) -> bool {
+    (irq == 7 || irq == 15) && read_isr() & (1 << irq) == 0
+}
+
+// Reads the CPU's time stamp counter
+fn timestamp() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Statistics of one interrupt line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqStats {
+    /// Number of interrupts delivered to the handlers
+    pub count: u64,
+    /// Number of spurious interrupts that were dropped
+    pub spurious: u64,
+    /// Time stamp counter when the line last fired
+    pub last_timestamp: u64,
+    /// Longest time the handlers took, in time stamp counter cycles
+    pub max_duration: u64,
+}
+
+const EMPTY_STATS: IrqStats = IrqStats {
+    count: 0,
+    spurious: 0,
+    last_timestamp: 0,
+    max_duration: 0,
+};
+
+static IRQ_STATS: IrqSpinLock<[IrqStats; IRQ_COUNT]> =
+    IrqSpinLock::new("irq_stats", [EMPTY_STATS; IRQ_COUNT]);
+
+/// Returns a snapshot of the statistics of `irq`
+pub fn irq_stats(irq: u8) -> Option<IrqStats> {
+    if usize::from(irq) >= IRQ_COUNT {
+        return None;
+    }
+    Some(IRQ_STATS.lock()[usize::from(irq)])
+}
+
+use core::fmt;
+
+/// Writes a table of every line that fired or has a handler, in the
+/// spirit of /proc/interrupts.
+pub fn write_irq_stats(writer: &mut impl fmt::Write) -> fmt::Result {
+    // Take snapshots first. Formatting may print to a locked device.
+    let stats = *IRQ_STATS.lock();
+    let handlers = *IRQ_HANDLERS.lock();
+
+    writeln!(writer, "IRQ  VEC        COUNT  SPURIOUS   MAX CYCLES  HANDLERS")?;
+    for irq in 0..IRQ_COUNT {
+        let handler_count = handlers[irq].iter().flatten().count();
+        let stats = &stats[irq];
+        if handler_count == 0 && stats.count == 0 && stats.spurious == 0 {
+            continue;
+        }
+        writeln!(
+            writer,
+            "{:>3}  {:>3}  {:>11}  {:>8}  {:>11}  {:>8}",
+            irq,
+            usize::from(PIC_1_OFFSET) + irq,
+            stats.count,
+            stats.spurious,
+            stats.max_duration,
+            handler_count,
+        )?;
+    }
+    Ok(())
+}
+
+/// Initializes the PICs with every line masked, then registers the
+/// built-in timer handler.
+pub fn init_pics() {
+    unsafe {
+        PICS.lock().initialize();
+    }
+
+    for irq in 0..IRQ_COUNT as u8 {
+        if irq != CASCADE_IRQ {
+            mask_irq(irq);
         }
     }
+    unmask_irq(CASCADE_IRQ);
+
+    register_irq(InterruptIndex::Timer.irq(), timer_interrupt_handler)
+        .expect("failed to register the timer handler");
+}
 
+/// Enables interrupts and halts until the next one.
+///
+/// `sti` only takes effect after the following instruction, so no
+/// interrupt can sneak in between checking for work and halting.
+pub fn enable_interrupts_and_hlt() {
     unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        asm!("sti; hlt" :::: "volatile");
     }
 }
 
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+// The PIT runs at 1.193182 MHz and divides by 65536 by default
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_DIVISOR: u64 = 65536;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Time since the timer interrupt was enabled
+pub fn uptime() -> Duration {
+    let micros = u128::from(ticks()) * u128::from(PIT_DIVISOR) * 1_000_000
+        / u128::from(PIT_FREQUENCY);
+    Duration::from_micros(micros as u64)
+}
+
 #[cfg(test)]
 use crate::{serial_print, serial_println};
 
@@ -145,4 +439,34 @@ fn test_breakpoint_exception() {
     // Trigger exception
     x86_64::instructions::interrupts::int3();
     serial_println!("[ok]");
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+fn test_irq_handler(_stack_frame: &mut InterruptStackFrame) {}
+
+#[test_case]
+fn test_register_irq() {
+    serial_print!("test_register_irq... ");
+    // IRQ 5 is not connected to anything under QEMU
+    assert_eq!(register_irq(5, test_irq_handler), Ok(()));
+    assert_eq!(unregister_irq(5, test_irq_handler), Ok(()));
+    assert_eq!(unregister_irq(5, test_irq_handler),
+               Err(IrqError::NotRegistered(5)));
+    assert_eq!(register_irq(16, test_irq_handler),
+               Err(IrqError::InvalidIrq(16)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_irq_stats() {
+    serial_print!("test_irq_stats... ");
+    let timer = InterruptIndex::Timer.irq();
+    let before = irq_stats(timer).unwrap().count;
+    // Only the timer can wake us up under test
+    x86_64::instructions::hlt();
+    let after = irq_stats(timer).unwrap();
+    assert!(after.count > before);
+    assert!(after.last_timestamp > 0);
+    assert_eq!(irq_stats(16), None);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,124 @@
+// Keyboard input as an asynchronous stream
+//
+// The interrupt handler only reads the scancode and pushes it into a
+// lock-free queue. Tasks consume the queue through ScancodeStream (raw
+// scancodes) or KeyStream (decoded keys).
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::interrupts::{self, InterruptIndex};
+use crate::keymap::{DecodedKey, KeyDecoder};
+use crate::ps2::{self, Leds};
+use crate::task::irq_stream::{IrqQueue, IrqStream};
+use crate::task::{Stream, StreamExt};
+use crate::{print, println};
+
+// Keyboard port of the PS/2 controller
+const KEYBOARD_DATA_PORT: u16 = 0x60;
+
+// Number of scancodes buffered before input is dropped
+const SCANCODE_QUEUE_SIZE: usize = 100;
+
+// Opened by scancode_stream, which needs the heap. Scancodes that arrive
+// before that are counted as dropped.
+static SCANCODES: IrqQueue<u8> = IrqQueue::new("scancode", SCANCODE_QUEUE_SIZE);
+
+/// Number of scancodes lost so far
+pub fn dropped_scancodes() -> u64 {
+    SCANCODES.dropped()
+}
+
+/// Registers the keyboard interrupt handler
+pub fn init() {
+    interrupts::register_irq(
+        InterruptIndex::Keyboard.irq(), keyboard_interrupt_handler)
+        .expect("failed to register the keyboard handler");
+}
+
+fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    // We need to read the scancode, otherwise, new keyboard events can't
+    // be handled.
+    let mut port = Port::new(KEYBOARD_DATA_PORT);
+    let scancode: u8 = unsafe { port.read() };
+    SCANCODES.push(scancode);
+}
+
+/// Stream of the raw scancodes sent by the keyboard
+pub type ScancodeStream = IrqStream<u8>;
+
+/// Opens the scancode stream. There can only be one reader, so this panics
+/// when called twice.
+pub fn scancode_stream() -> ScancodeStream {
+    SCANCODES.open()
+}
+
+/// Stream of key presses, decoded with the active keymap. Also keeps the
+/// keyboard LEDs in sync with the lock keys.
+pub struct KeyStream {
+    scancodes: ScancodeStream,
+    decoder: KeyDecoder,
+    leds: Leds,
+}
+
+impl KeyStream {
+    pub fn new() -> Self {
+        KeyStream {
+            scancodes: scancode_stream(),
+            decoder: KeyDecoder::new(),
+            leds: Leds::default(),
+        }
+    }
+
+    fn update_leds(&mut self) {
+        let modifiers = self.decoder.modifiers();
+        let leds = Leds {
+            scroll_lock: modifiers.scroll_lock,
+            num_lock: modifiers.num_lock,
+            caps_lock: modifiers.caps_lock,
+        };
+        if leds != self.leds {
+            self.leds = leds;
+            if let Err(err) = ps2::set_leds(leds) {
+                println!("WARNING: failed to set keyboard LEDs: {:?}", err);
+            }
+        }
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = DecodedKey;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Option<DecodedKey>> {
+        let this = self.get_mut();
+        if let Some(key) = this.decoder.take_pending() {
+            return Poll::Ready(Some(key));
+        }
+        loop {
+            let scancode = match Pin::new(&mut this.scancodes).poll_next(cx) {
+                Poll::Ready(Some(scancode)) => scancode,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            // Most scancodes (releases, modifiers) don't produce a key
+            let key = this.decoder.add_byte(scancode);
+            this.update_leds();
+            if let Some(key) = key {
+                return Poll::Ready(Some(key));
+            }
+        }
+    }
+}
+
+/// Task that prints every key press to the screen
+pub async fn print_keypresses() {
+    let mut keys = KeyStream::new();
+    while let Some(key) = keys.next().await {
+        match key {
+            DecodedKey::Unicode(character) => print!("{}", character),
+            DecodedKey::RawKey(key) => print!("{:?}", key),
+        }
+    }
+}
@@ -0,0 +1,545 @@
+// Keymaps and scancode decoding
+//
+// Scancodes (set 1) are turned into keycodes first: the make code of the
+// key, with 0x80 set for keys sent with an 0xE0 prefix. The active keymap
+// then maps keycodes to characters. Modifiers, lock keys and dead keys are
+// layout independent and handled by KeyDecoder.
+use lazy_static::lazy_static;
+
+use crate::spinlock::IrqSpinLock;
+
+/// Keys that don't produce a character
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+    ArrowUp,
+    ArrowDown,
+    ArrowLeft,
+    ArrowRight,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodedKey {
+    Unicode(char),
+    RawKey(KeyCode),
+}
+
+/// Modifier combination used to pick a character from a keymap entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Normal = 0,
+    Shift = 1,
+    AltGr = 2,
+    ShiftAltGr = 3,
+}
+
+const LEVEL_COUNT: usize = 4;
+
+// Keymaps only cover keys without the 0xE0 prefix
+const KEYMAP_SIZE: usize = 0x80;
+
+// Marks an empty entry in keymaps and layout rows
+const NO_CHAR: char = '\0';
+
+/// Table from keycode and level to character
+pub struct Keymap {
+    name: &'static str,
+    keys: [[char; LEVEL_COUNT]; KEYMAP_SIZE],
+}
+
+impl Keymap {
+    /// Creates a keymap without any character keys
+    pub const fn empty(name: &'static str) -> Self {
+        Keymap {
+            name,
+            keys: [[NO_CHAR; LEVEL_COUNT]; KEYMAP_SIZE],
+        }
+    }
+
+    /// Creates a keymap from the characters of each key row
+    pub fn from_rows(name: &'static str, rows: &LayoutRows) -> Self {
+        let mut keymap = Keymap::empty(name);
+        let levels = [
+            (Level::Normal, &rows.normal),
+            (Level::Shift, &rows.shift),
+            (Level::AltGr, &rows.altgr),
+        ];
+        for &(level, level_rows) in levels.iter() {
+            for (keycodes, row) in ROW_KEYCODES.iter().zip(level_rows.iter()) {
+                for (&keycode, character) in keycodes.iter().zip(row.chars()) {
+                    if character != ' ' {
+                        keymap.set(keycode, level, character);
+                    }
+                }
+            }
+        }
+        keymap
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Sets the character of a key. Dead keys are entered as the
+    /// corresponding combining character, e.g. '\u{302}' for a dead '^'.
+    pub fn set(&mut self, keycode: u8, level: Level, character: char) {
+        if let Some(entry) = self.keys.get_mut(usize::from(keycode)) {
+            entry[level as usize] = character;
+        }
+    }
+
+    pub fn get(&self, keycode: u8, level: Level) -> Option<char> {
+        let entry = self.keys.get(usize::from(keycode))?;
+        match entry[level as usize] {
+            NO_CHAR => None,
+            character => Some(character),
+        }
+    }
+}
+
+// Keycodes of the main key block, in the order of LayoutRows strings:
+// the number row, the top row (plus the key that is above Enter on ANSI
+// and left of Enter on ISO keyboards), the home row, and the bottom row
+// (starting with the extra ISO key left of Z).
+const ROW_KEYCODES: [&[u8]; 4] = [
+    &[0x29, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b,
+      0x0c, 0x0d],
+    &[0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a,
+      0x1b, 0x2b],
+    &[0x1e, 0x1f, 0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28],
+    &[0x56, 0x2c, 0x2d, 0x2e, 0x2f, 0x30, 0x31, 0x32, 0x33, 0x34, 0x35],
+];
+
+/// Characters of the main key block, one string per key row (see
+/// ROW_KEYCODES) and level. A space means the key has no character on
+/// that level.
+pub struct LayoutRows {
+    pub normal: [&'static str; 4],
+    pub shift: [&'static str; 4],
+    pub altgr: [&'static str; 4],
+}
+
+pub const US104_ROWS: LayoutRows = LayoutRows {
+    normal: ["`1234567890-=", "qwertyuiop[]\\", "asdfghjkl;'", "\\zxcvbnm,./"],
+    shift: ["~!@#$%^&*()_+", "QWERTYUIOP{}|", "ASDFGHJKL:\"", "|ZXCVBNM<>?"],
+    altgr: ["", "", "", ""],
+};
+
+pub const UK105_ROWS: LayoutRows = LayoutRows {
+    normal: ["`1234567890-=", "qwertyuiop[]#", "asdfghjkl;'", "\\zxcvbnm,./"],
+    shift: ["¬!\"£$%^&*()_+", "QWERTYUIOP{}~", "ASDFGHJKL:@", "|ZXCVBNM<>?"],
+    altgr: ["¦   €", "  é   úíó", "á", ""],
+};
+
+pub const DVORAK_ROWS: LayoutRows = LayoutRows {
+    normal: ["`1234567890[]", "',.pyfgcrl/=\\", "aoeuidhtns-", "\\;qjkxbmwvz"],
+    shift: ["~!@#$%^&*(){}", "\"<>PYFGCRL?+|", "AOEUIDHTNS_", "|:QJKXBMWVZ"],
+    altgr: ["", "", "", ""],
+};
+
+// French AZERTY. The key right of P has a dead circumflex and diaeresis,
+// AltGr on é and è gives a dead tilde and grave.
+pub const AZERTY_ROWS: LayoutRows = LayoutRows {
+    normal: ["²&é\"'(-è_çà)=", "azertyuiop\u{302}$*", "qsdfghjklmù",
+             "<wxcvbn,;:!"],
+    shift: [" 1234567890°+", "AZERTYUIOP\u{308}£µ", "QSDFGHJKLM%",
+            ">WXCVBN?./§"],
+    altgr: ["  \u{303}#{[|\u{300}\\^@]}", "  €", "", ""],
+};
+
+// German QWERTZ. ^ left of 1 and ´/` left of backspace are dead keys.
+pub const QWERTZ_ROWS: LayoutRows = LayoutRows {
+    normal: ["\u{302}1234567890ß\u{301}", "qwertzuiopü+#", "asdfghjklöä",
+             "<yxcvbnm,.-"],
+    shift: ["°!\"§$%&/()=?\u{300}", "QWERTZUIOPÜ*'", "ASDFGHJKLÖÄ",
+            ">YXCVBNM;:_"],
+    altgr: ["  ²³   {[]}\\", "@ €        ~", "", "|      µ"],
+};
+
+/// The built-in layouts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us104,
+    Uk105,
+    Dvorak,
+    Azerty,
+    Qwertz,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 5] = [
+        Layout::Us104, Layout::Uk105, Layout::Dvorak, Layout::Azerty,
+        Layout::Qwertz,
+    ];
+
+    pub fn keymap(self) -> &'static Keymap {
+        match self {
+            Layout::Us104 => &US104,
+            Layout::Uk105 => &UK105,
+            Layout::Dvorak => &DVORAK,
+            Layout::Azerty => &AZERTY,
+            Layout::Qwertz => &QWERTZ,
+        }
+    }
+
+    /// Looks a layout up by its keymap name, e.g. "de"
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL.iter().cloned().find(|l| l.keymap().name() == name)
+    }
+}
+
+lazy_static! {
+    static ref US104: Keymap = Keymap::from_rows("us", &US104_ROWS);
+    static ref UK105: Keymap = Keymap::from_rows("uk", &UK105_ROWS);
+    static ref DVORAK: Keymap = Keymap::from_rows("dvorak", &DVORAK_ROWS);
+    static ref AZERTY: Keymap = Keymap::from_rows("fr", &AZERTY_ROWS);
+    static ref QWERTZ: Keymap = Keymap::from_rows("de", &QWERTZ_ROWS);
+
+    static ref ACTIVE_KEYMAP: IrqSpinLock<&'static Keymap> =
+        IrqSpinLock::new("keymap", &*US104);
+}
+
+/// Returns the keymap used to decode keyboard input
+pub fn active_keymap() -> &'static Keymap {
+    *ACTIVE_KEYMAP.lock()
+}
+
+/// Switches keyboard input to one of the built-in layouts
+pub fn set_layout(layout: Layout) {
+    set_keymap(layout.keymap());
+}
+
+/// Switches keyboard input to a custom keymap
+pub fn set_keymap(keymap: &'static Keymap) {
+    *ACTIVE_KEYMAP.lock() = keymap;
+}
+
+/// Installs a keymap built at runtime. Keymaps in use can't be freed, so
+/// the keymap is leaked.
+pub fn load_keymap(keymap: alloc::boxed::Box<Keymap>) {
+    set_keymap(alloc::boxed::Box::leak(keymap));
+}
+
+// Combining characters used for dead keys, with their spacing version and
+// the letters they compose with.
+const DEAD_KEYS: [(char, char, &str, &str); 5] = [
+    ('\u{300}', '`', "aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+    ('\u{301}', '´', "aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+    ('\u{302}', '^', "aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+    ('\u{303}', '~', "anoANO", "ãñõÃÑÕ"),
+    ('\u{308}', '¨', "aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+];
+
+fn dead_key(character: char) -> Option<&'static (char, char, &'static str, &'static str)> {
+    DEAD_KEYS.iter().find(|dead| dead.0 == character)
+}
+
+fn compose(dead: char, base: char) -> Option<char> {
+    let (_, _, bases, composed) = dead_key(dead)?;
+    let index = bases.chars().position(|c| c == base)?;
+    composed.chars().nth(index)
+}
+
+/// State of the modifier and lock keys
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub alt: bool,
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+}
+
+// Prefix of extended scancodes
+const EXTENDED: u8 = 0xe0;
+// Prefix of the Pause key sequence, E1 1D 45 E1 9D C5
+const PAUSE: u8 = 0xe1;
+// Set in a scancode when the key is released
+const RELEASED: u8 = 0x80;
+// Caps Lock, Num Lock and Scroll Lock
+const LOCK_KEYS: [u8; 3] = [0x3a, 0x45, 0x46];
+
+/// Decodes scancode set 1 into key presses using the active keymap
+#[derive(Debug, Default)]
+pub struct KeyDecoder {
+    modifiers: Modifiers,
+    // The previous byte was the 0xE0 prefix
+    extended: bool,
+    // Bytes of a Pause sequence still to skip
+    skip: u8,
+    // Combining character of a dead key waiting for the next key
+    dead_key: Option<char>,
+    // Key to return after the current one
+    pending: Option<DecodedKey>,
+    // Which of LOCK_KEYS are held down
+    lock_keys_held: [bool; 3],
+}
+
+impl KeyDecoder {
+    pub fn new() -> Self {
+        KeyDecoder::default()
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    /// Returns a key that was decoded together with the previous one, e.g.
+    /// the letter after a dead key it doesn't compose with.
+    pub fn take_pending(&mut self) -> Option<DecodedKey> {
+        self.pending.take()
+    }
+
+    /// Feeds one scancode byte and returns the key it completes, if any
+    pub fn add_byte(&mut self, byte: u8) -> Option<DecodedKey> {
+        self.add_byte_with(byte, active_keymap())
+    }
+
+    pub fn add_byte_with(&mut self, byte: u8, keymap: &Keymap)
+        -> Option<DecodedKey> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return None;
+        }
+        match byte {
+            EXTENDED => {
+                self.extended = true;
+                return None;
+            }
+            PAUSE => {
+                self.skip = 2;
+                return None;
+            }
+            _ => {}
+        }
+
+        let released = byte & RELEASED != 0;
+        let keycode = if self.extended {
+            byte | RELEASED
+        } else {
+            byte & !RELEASED
+        };
+        self.extended = false;
+
+        if self.update_modifiers(keycode, released) || released {
+            return None;
+        }
+        self.decode(keycode, keymap)
+    }
+
+    // Returns true if keycode is a modifier or lock key
+    fn update_modifiers(&mut self, keycode: u8, released: bool) -> bool {
+        let pressed = !released;
+        if let Some(index) = LOCK_KEYS.iter().position(|&k| k == keycode) {
+            // Held keys repeat their make code, only the first one toggles
+            let toggle = pressed && !self.lock_keys_held[index];
+            self.lock_keys_held[index] = pressed;
+            if toggle {
+                let m = &mut self.modifiers;
+                match index {
+                    0 => m.caps_lock = !m.caps_lock,
+                    1 => m.num_lock = !m.num_lock,
+                    _ => m.scroll_lock = !m.scroll_lock,
+                }
+            }
+            return true;
+        }
+
+        let m = &mut self.modifiers;
+        match keycode {
+            0x2a => m.left_shift = pressed,
+            0x36 => m.right_shift = pressed,
+            0x1d => m.left_ctrl = pressed,
+            0x9d => m.right_ctrl = pressed,
+            0x38 => m.alt = pressed,
+            0xb8 => m.alt_gr = pressed,
+            // Fake shifts sent around extended keys like Print Screen
+            0xaa | 0xb6 => {}
+            _ => return false,
+        }
+        true
+    }
+
+    fn decode(&mut self, keycode: u8, keymap: &Keymap) -> Option<DecodedKey> {
+        use self::DecodedKey::{RawKey, Unicode};
+
+        let key = match keycode {
+            0x01 => Unicode('\x1b'),
+            0x0e => Unicode('\x08'),
+            0x0f => Unicode('\t'),
+            0x1c | 0x9c => Unicode('\n'),
+            0x39 => Unicode(' '),
+            0x3b..=0x44 => RawKey(FUNCTION_KEYS[usize::from(keycode - 0x3b)]),
+            0x57 => RawKey(KeyCode::F11),
+            0x58 => RawKey(KeyCode::F12),
+            0xc8 => RawKey(KeyCode::ArrowUp),
+            0xd0 => RawKey(KeyCode::ArrowDown),
+            0xcb => RawKey(KeyCode::ArrowLeft),
+            0xcd => RawKey(KeyCode::ArrowRight),
+            0xc7 => RawKey(KeyCode::Home),
+            0xcf => RawKey(KeyCode::End),
+            0xc9 => RawKey(KeyCode::PageUp),
+            0xd1 => RawKey(KeyCode::PageDown),
+            0xd2 => RawKey(KeyCode::Insert),
+            0xd3 => Unicode('\x7f'),
+            0xb5 => Unicode('/'),
+            0x37 => Unicode('*'),
+            0x4a => Unicode('-'),
+            0x4e => Unicode('+'),
+            // Keypad without Num Lock acts like the extended keys
+            0x47..=0x53 if !self.modifiers.num_lock => {
+                return self.decode(keycode | RELEASED, keymap);
+            }
+            0x47..=0x53 => match KEYPAD[usize::from(keycode - 0x47)] {
+                NO_CHAR => return None,
+                character => Unicode(character),
+            },
+            _ => return self.decode_character(keycode, keymap),
+        };
+        Some(key)
+    }
+
+    fn decode_character(&mut self, keycode: u8, keymap: &Keymap)
+        -> Option<DecodedKey> {
+        let m = self.modifiers;
+        let plain = keymap.get(keycode, Level::Normal)?;
+
+        // Caps Lock works like Shift, but only on letters
+        let shift = m.shift() ^ (m.caps_lock && plain.is_alphabetic());
+        let level = match (shift, m.alt_gr) {
+            (false, false) => Level::Normal,
+            (true, false) => Level::Shift,
+            (false, true) => Level::AltGr,
+            (true, true) => Level::ShiftAltGr,
+        };
+        let character = keymap.get(keycode, level)?;
+
+        if m.ctrl() && character.is_ascii_alphabetic() {
+            // Ctrl-A is 0x01 up to Ctrl-Z 0x1a
+            let control = character.to_ascii_lowercase() as u8 - b'a' + 1;
+            return Some(DecodedKey::Unicode(char::from(control)));
+        }
+
+        self.apply_dead_key(character).map(DecodedKey::Unicode)
+    }
+
+    fn apply_dead_key(&mut self, character: char) -> Option<char> {
+        let previous = self.dead_key.take();
+
+        if let Some(&(_, spacing, _, _)) = dead_key(character) {
+            if previous == Some(character) {
+                // Pressing a dead key twice types the accent itself
+                return Some(spacing);
+            }
+            self.dead_key = Some(character);
+            return None;
+        }
+
+        let dead = match previous {
+            Some(dead) => dead,
+            None => return Some(character),
+        };
+        let spacing = dead_key(dead).map(|d| d.1).unwrap_or(dead);
+        if character == ' ' {
+            return Some(spacing);
+        }
+        match compose(dead, character) {
+            Some(composed) => Some(composed),
+            None => {
+                // Type the accent and then the key
+                self.pending = Some(DecodedKey::Unicode(character));
+                Some(spacing)
+            }
+        }
+    }
+}
+
+const FUNCTION_KEYS: [KeyCode; 10] = [
+    KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4, KeyCode::F5,
+    KeyCode::F6, KeyCode::F7, KeyCode::F8, KeyCode::F9, KeyCode::F10,
+];
+
+// Keypad keys 0x47 to 0x53 with Num Lock on. 0x4a and 0x4e are handled
+// separately.
+const KEYPAD: [char; 13] = [
+    '7', '8', '9', NO_CHAR, '4', '5', '6', NO_CHAR, '1', '2', '3', '0', '.',
+];
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[cfg(test)]
+fn decode_all(decoder: &mut KeyDecoder, bytes: &[u8], keymap: &Keymap)
+    -> Option<DecodedKey> {
+    bytes.iter().fold(None, |_, &b| decoder.add_byte_with(b, keymap))
+}
+
+#[test_case]
+fn test_decode_us104() {
+    serial_print!("test_decode_us104... ");
+    let mut decoder = KeyDecoder::new();
+    let us = Layout::Us104.keymap();
+    // a, then a with left shift held
+    assert_eq!(decode_all(&mut decoder, &[0x1e, 0x9e], us), None);
+    assert_eq!(decoder.add_byte_with(0x1e, us), Some(DecodedKey::Unicode('a')));
+    assert_eq!(decode_all(&mut decoder, &[0x2a, 0x1e], us),
+               Some(DecodedKey::Unicode('A')));
+    // Ctrl-C after releasing shift
+    assert_eq!(decode_all(&mut decoder, &[0xaa, 0x1d, 0x2e], us),
+               Some(DecodedKey::Unicode('\x03')));
+    assert_eq!(decode_all(&mut decoder, &[0x9d, 0xe0, 0x48], us),
+               Some(DecodedKey::RawKey(KeyCode::ArrowUp)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_decode_qwertz_dead_keys() {
+    serial_print!("test_decode_qwertz_dead_keys... ");
+    let mut decoder = KeyDecoder::new();
+    let de = Layout::Qwertz.keymap();
+    // z and y are swapped
+    assert_eq!(decoder.add_byte_with(0x15, de), Some(DecodedKey::Unicode('z')));
+    // dead ^ followed by e
+    assert_eq!(decoder.add_byte_with(0x29, de), None);
+    assert_eq!(decoder.add_byte_with(0x12, de), Some(DecodedKey::Unicode('ê')));
+    // dead ^ followed by x doesn't compose
+    assert_eq!(decoder.add_byte_with(0x29, de), None);
+    assert_eq!(decoder.add_byte_with(0x2d, de), Some(DecodedKey::Unicode('^')));
+    assert_eq!(decoder.take_pending(), Some(DecodedKey::Unicode('x')));
+    // AltGr-Q
+    assert_eq!(decode_all(&mut decoder, &[0xe0, 0x38, 0x10], de),
+               Some(DecodedKey::Unicode('@')));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_decode_qwertz_altgr_numbers() {
+    serial_print!("test_decode_qwertz_altgr_numbers... ");
+    let mut decoder = KeyDecoder::new();
+    let de = Layout::Qwertz.keymap();
+    // AltGr-2, then 7 and ß with AltGr still held
+    assert_eq!(decode_all(&mut decoder, &[0xe0, 0x38, 0x03], de),
+               Some(DecodedKey::Unicode('²')));
+    assert_eq!(decoder.add_byte_with(0x08, de), Some(DecodedKey::Unicode('{')));
+    assert_eq!(decoder.add_byte_with(0x0c, de), Some(DecodedKey::Unicode('\\')));
+    serial_println!("[ok]");
+}
@@ -13,6 +13,9 @@
 
 #![feature(alloc_error_handler)]
 
+// Inline assembly for instructions the x86_64 crate doesn't wrap
+#![feature(asm)]
+
 extern crate alloc;
 
 // Make print and serial_print available
@@ -23,16 +26,34 @@ pub mod interrupts;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
-
-use linked_list_allocator::LockedHeap;
+pub mod work_queue;
+pub mod spinlock;
+pub mod watchdog;
+pub mod keyboard;
+pub mod keymap;
+pub mod ps2;
+pub mod mouse;
+pub mod task;
+pub mod tty;
+pub mod shell;
+pub mod power;
+pub mod mmio;
+
+use allocator::{KernelHeap, TrackingAllocator};
+use spinlock::IrqSpinLock;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: TrackingAllocator<IrqSpinLock<KernelHeap>> =
+    TrackingAllocator::new(IrqSpinLock::new("heap", KernelHeap::empty()));
 //static ALLOCATOR: allocator::Dummy = allocator::Dummy;
 
+// Besides running out of memory, an allocation fails when the heap has to
+// grow while the caller holds MAPPER or FRAME_ALLOCATOR, see
+// allocator::skipped_resizes
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("allocation error: {:?}", layout)
+    panic!("allocation error: {:?} (heap resizes skipped: {})",
+           layout, allocator::skipped_resizes())
 }
 
 pub fn init() {
@@ -42,10 +63,21 @@ pub fn init() {
     // Initialize the IDT
     interrupts::init_idt();
 
-    // Initialize the PIC
-    unsafe {
-        interrupts::PICS.lock().initialize()
-    };
+    // Initialize the PIC and register the built-in IRQ handlers
+    interrupts::init_pics();
+
+    // Report hangs instead of waiting for the test timeout
+    watchdog::init();
+
+    // Set up the PS/2 controller before the keyboard interrupt is enabled
+    if let Err(err) = ps2::init() {
+        println!("PS/2 controller initialization failed: {:?}", err);
+    }
+    keyboard::init();
+    serial::init();
+    if let Err(err) = mouse::init() {
+        println!("PS/2 mouse initialization failed: {:?}", err);
+    }
     x86_64::instructions::interrupts::enable();
 }
 
@@ -67,8 +99,12 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 }
 
 // Avoid the loop {} consumes CPU
+// Also acts as the worker loop for deferred interrupt work. Work queued
+// while we are halted runs at the end of the interrupt that queued it.
 pub fn hlt_loop() -> ! {
     loop {
+        watchdog::touch();
+        work_queue::run_pending_work();
         x86_64::instructions::hlt();
     }
 }
@@ -79,6 +115,7 @@ use core::panic::PanicInfo;
 pub fn test_runner(tests: &[&dyn Fn()]) {
     serial_println!("Running {} tests", tests.len());
     for test in tests {
+        watchdog::touch();
         test();
     }
     exit_qemu(QemuExitCode::Success);
@@ -92,6 +129,22 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     hlt_loop();
 }
 
+/// Sets the kernel up the way kernel_main does, for the integration tests
+/// that need MAPPER, FRAME_ALLOCATOR and the heap
+pub fn init_for_tests(boot_info: &'static bootloader::BootInfo) {
+    init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe {
+        memory::BitmapFrameAllocator::init(&boot_info.memory_map, boot_info.physical_memory_offset)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::address_space::init_kernel_entries(&mut frame_allocator)
+        .expect("failed to set up the kernel P4 entries");
+    *memory::MAPPER.lock() = Some(mapper);
+    *memory::FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
 #[cfg(test)]
 use bootloader::{entry_point, BootInfo};
 
@@ -50,14 +50,31 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use near_os::memory;
     use x86_64::{VirtAddr, structures::paging::Page};
 
+    // No two users of kernel virtual memory may overlap
+    memory::vmalloc::check_regions();
     let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    // Before anything else is mapped, so the heap gets NX too
+    memory::protect_kernel(&mut mapper).expect("failed to protect the kernel image");
     let mut frame_allocator = unsafe {
-        memory::BootInfoFrameAllocator::init(&boot_info.memory_map)
+        memory::BitmapFrameAllocator::init(
+            &boot_info.memory_map, boot_info.physical_memory_offset)
+    };
+    let buddy_allocator = unsafe {
+        memory::BuddyAllocator::init(
+            &boot_info.memory_map, &mut frame_allocator,
+            boot_info.physical_memory_offset, memory::CONTIGUOUS_POOL_SIZE)
     };
 
     use near_os::allocator;
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    // Before any address space copies the kernel's P4 entries
+    memory::address_space::init_kernel_entries(&mut frame_allocator)
+        .expect("failed to set up the kernel P4 entries");
+    // Hand the remaining frames to the rest of the kernel
+    *memory::MAPPER.lock() = Some(mapper);
+    *memory::FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    *memory::BUDDY_ALLOCATOR.lock() = Some(buddy_allocator);
 
     // allocate a number on the heap
     let heap_value = Box::new(41);
@@ -77,6 +94,16 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     core::mem::drop(reference_counted);
     println!("reference count is {} now", Rc::strong_count(&cloned_reference));let x = Box::new(42);
 
+    use near_os::task::{Task, executor::Executor};
+    use near_os::mouse;
+    use near_os::shell;
+    use near_os::tty::Tty;
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(shell::run(Tty::console())));
+    executor.spawn(Task::new(shell::run(Tty::serial())));
+    executor.spawn(Task::new(mouse::track_cursor()));
+
     // Only run while testing
     #[cfg(test)]
     test_main(); // This function is auto-generated
@@ -90,5 +117,5 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // }
 
     println!("Not Crash");
-    near_os::hlt_loop();
+    executor.run();
 }
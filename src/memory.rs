@@ -1,16 +1,48 @@
 use x86_64::structures::paging::PageTable;
 use x86_64::{PhysAddr, VirtAddr};
-use x86_64::structures::paging::{PhysFrame, MapperAllSizes, MappedPageTable};
+use x86_64::structures::paging::{PhysFrame, MapperAllSizes, OffsetPageTable};
 
-/// Initialize a new MappedPageTable
-pub unsafe fn init(physical_memory_offset: u64) -> impl MapperAllSizes {
+pub mod address_space;
+pub mod bitmap;
+pub mod buddy;
+pub mod dump;
+pub mod protect;
+pub mod slab;
+pub mod unmap;
+pub mod vmalloc;
+pub mod walk;
+
+pub use address_space::AddressSpace;
+pub use bitmap::{BitmapFrameAllocator, FrameStats};
+pub use buddy::{BuddyAllocator, Constraints};
+pub use protect::{no_execute, protect_kernel};
+pub use slab::{SlabCache, SlabStats};
+pub use unmap::{unmap_range, UnmapRangeError, UnmapStats};
+pub use vmalloc::{ioremap, iounmap, vfree, vmalloc, VmError};
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// Where the bootloader mapped all of physical memory
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+pub fn physical_memory_offset() -> u64 {
+    PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed)
+}
+
+// Physical address of the kernel's own P4 table, the one active at boot
+static KERNEL_LEVEL_4_TABLE: AtomicU64 = AtomicU64::new(0);
+
+pub fn kernel_level_4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4_TABLE.load(Ordering::Relaxed)))
+}
+
+/// Initialize a new OffsetPageTable
+pub unsafe fn init(physical_memory_offset: u64) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset, Ordering::Relaxed);
+    let (kernel_table, _) = x86_64::registers::control::Cr3::read();
+    KERNEL_LEVEL_4_TABLE.store(kernel_table.start_address().as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
-    let phys_to_virt = move |frame: PhysFrame| -> *mut PageTable {
-        let phys = frame.start_address().as_u64();
-        let virt = VirtAddr::new(phys + physical_memory_offset);
-        virt.as_mut_ptr()
-    };
-    MappedPageTable::new(level_4_table, phys_to_virt)
+    OffsetPageTable::new(level_4_table, VirtAddr::new(physical_memory_offset))
 }
 
 /// Returns a mutable reference to the active level 4 table.
@@ -36,6 +68,8 @@ unsafe fn active_level_4_table(physical_memory_offset: u64)
 }
 
 use x86_64::structures::paging::{Page, Size4KiB, Mapper, FrameAllocator};
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size1GiB, Size2MiB};
+use x86_64::structures::paging::mapper::MapToError;
 
 pub fn create_example_mapping(
     page: Page,
@@ -45,7 +79,9 @@ pub fn create_example_mapping(
     use x86_64::structures::paging::PageTableFlags as Flags;
 
     let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
-    let flags = Flags::PRESENT | Flags::WRITABLE;
+    // Device memory, keep it out of the cache. mmio::map does this for
+    // real drivers.
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE | Flags::WRITE_THROUGH;
 
     let map_to_result = unsafe {
         mapper.map_to(page, frame, flags, frame_allocator)
@@ -99,6 +135,39 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     }
 }
 
+use crate::spinlock::IrqSpinLock;
+
+/// Frame allocator for everything that needs frames after boot. Set once
+/// the boot code has finished setting up the heap.
+pub static FRAME_ALLOCATOR: IrqSpinLock<Option<BitmapFrameAllocator>> =
+    IrqSpinLock::new("frame_allocator", None);
+
+/// Mapper for the kernel page tables. Set by the boot code together with
+/// FRAME_ALLOCATOR. Take this lock before FRAME_ALLOCATOR when both are
+/// needed.
+pub static MAPPER: IrqSpinLock<Option<OffsetPageTable<'static>>> =
+    IrqSpinLock::new("mapper", None);
+
+/// Pool of physically contiguous memory for DMA buffers and the like
+pub static BUDDY_ALLOCATOR: IrqSpinLock<Option<BuddyAllocator>> =
+    IrqSpinLock::new("buddy_allocator", None);
+
+/// Size of the buddy allocator's pool
+pub const CONTIGUOUS_POOL_SIZE: u64 = 8 * 1024 * 1024;
+
+// Hands out single frames only, so huge mappings fall back to 4 KiB pages
+unsafe impl FrameAllocator<Size2MiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        None
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        None
+    }
+}
+
 // Dummy Allocator
 pub struct EmptyFrameAllocator;
 
@@ -111,46 +180,199 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
 // ===============================================================
 // ===============================================================
 
-/// Translates the virtual address to physical address or None if not mapped.
-/// Our own implementation
-unsafe fn _translate_addr(addr: VirtAddr, physical_memory_offset: u64)
-    -> Option<PhysAddr> {
-    _translate_addr_inner(addr, physical_memory_offset)
+use x86_64::structures::paging::page_table::PageTableEntry;
+use walk::Visitor;
+
+// Follows the path to one address
+struct PathWalk<F> {
+    addr: u64,
+    visit: F,
+    phys: Option<PhysAddr>,
 }
 
-// Private implemetation.
-fn _translate_addr_inner(addr: VirtAddr, physical_memory_offset: u64)
+impl<F: FnMut(u8, &PageTableEntry)> Visitor for PathWalk<F> {
+    type Error = ();
+
+    fn leaf(&mut self, entry: &mut PageTableEntry, level: u8, _start: u64) -> Result<(), ()> {
+        (self.visit)(level, entry);
+        let offset = self.addr & (walk::entry_size(level) - 1);
+        self.phys = Some(entry.addr() + offset);
+        Ok(())
+    }
+
+    fn enter(&mut self, entry: &PageTableEntry, level: u8, _start: u64) -> bool {
+        (self.visit)(level, entry);
+        true
+    }
+
+    fn absent(&mut self, entry: &PageTableEntry, level: u8, _start: u64) {
+        (self.visit)(level, entry);
+    }
+}
+
+/// Walks the active page tables for `addr`, calling `visit` with the level
+/// (4 to 1) and the entry used at each step.
+///
+/// Returns the physical address, or None if it isn't mapped. Huge pages
+/// end the walk early at P3 (1 GiB) or P2 (2 MiB).
+pub fn walk_page_tables(addr: VirtAddr, visit: impl FnMut(u8, &PageTableEntry))
     -> Option<PhysAddr> {
-    
-    use x86_64::structures::paging::page_table::FrameError;
     use x86_64::registers::control::Cr3;
 
-    // Read the active level 4 frame from the CR3 register
-    let (level_4_table_frame, _) = Cr3::read();
+    let addr = addr.as_u64();
+    let mut path = PathWalk { addr, visit, phys: None };
+    let table = unsafe { walk::table_at(Cr3::read().0) };
+    let _ = walk::walk(table, addr, addr.saturating_add(1), &mut path);
+    path.phys
+}
+
+/// Translates `addr` with the active page tables
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    walk_page_tables(addr, |_, _| {})
+}
+
+/// Flags of the last entry the walk for `addr` reaches, the one that maps
+/// it if it is mapped
+pub fn leaf_flags(addr: VirtAddr) -> x86_64::structures::paging::PageTableFlags {
+    let mut flags = x86_64::structures::paging::PageTableFlags::empty();
+    walk_page_tables(addr, |_, entry| flags = entry.flags());
+    flags
+}
+
+/// Frames FRAME_ALLOCATOR has left
+pub fn free_frames() -> usize {
+    FRAME_ALLOCATOR.lock().as_ref().expect("no frame allocator").stats().free
+}
 
-    // Index of each level of page table
-    let table_indexes = [
-        addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
-    ];
-
-    let mut frame = level_4_table_frame;
-
-    // traverse the multi-level page table
-    for &index in &table_indexes {
-        // convert the frame into a page table reference
-        let virt = frame.start_address().as_u64() + physical_memory_offset;
-        let table_ptr: *const PageTable = VirtAddr::new(virt).as_ptr();
-        let table = unsafe {&*table_ptr};
-
-        // read the page table entry and update frame
-        let entry = &table[index];
-        frame = match entry.frame() {
-            Ok(frame) => frame,
-            Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("hube pages not supported"),
-        };
+// ===============================================================
+// Huge pages
+
+use spin::Once;
+
+static GIGABYTE_PAGES: Once<bool> = Once::new();
+
+/// Whether the CPU can map 1 GiB pages (CPUID PDPE1GB)
+pub fn supports_1gib_pages() -> bool {
+    *GIGABYTE_PAGES.call_once(|| {
+        use core::arch::x86_64::__cpuid;
+
+        let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+        max_extended_leaf >= 0x8000_0001
+            && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+    })
+}
+
+// Largest page that starts at `virt` (and `phys`, if given) and fits
+fn largest_page_size(virt: u64, phys: Option<u64>, remaining: u64) -> u64 {
+    let aligned = |size: u64| {
+        virt % size == 0 && phys.map_or(true, |phys| phys % size == 0) && remaining >= size
+    };
+    if supports_1gib_pages() && aligned(Size1GiB::SIZE) {
+        Size1GiB::SIZE
+    } else if aligned(Size2MiB::SIZE) {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
     }
+}
+
+unsafe fn map_page<S, M, A>(mapper: &mut M, virt: u64, phys: PhysAddr,
+                            flags: PageTableFlags, frame_allocator: &mut A)
+    -> Result<(), MapToError>
+where
+    S: PageSize,
+    M: Mapper<S>,
+    A: FrameAllocator<Size4KiB>,
+{
+    let page = Page::<S>::containing_address(VirtAddr::new(virt));
+    let frame = PhysFrame::<S>::containing_address(phys);
+    mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+    Ok(())
+}
+
+/// Maps `size` bytes at `start` to newly allocated frames, using 1 GiB and
+/// 2 MiB pages where the range is aligned for them and the allocator has
+/// huge frames left.
+pub fn map_range<M, A>(mapper: &mut M, frame_allocator: &mut A, start: VirtAddr,
+                       size: u64, flags: PageTableFlags)
+    -> Result<(), MapToError>
+where
+    M: MapperAllSizes,
+    A: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB> + FrameAllocator<Size1GiB>,
+{
+    assert!(start.as_u64() % Size4KiB::SIZE == 0, "unaligned start {:?}", start);
+    let size = (size + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1);
+
+    let mut offset = 0;
+    while offset < size {
+        let virt = start.as_u64() + offset;
+        let page_size = largest_page_size(virt, None, size - offset);
+
+        if page_size == Size1GiB::SIZE {
+            let frame = <A as FrameAllocator<Size1GiB>>::allocate_frame(frame_allocator);
+            if let Some(frame) = frame {
+                unsafe {
+                    map_page::<Size1GiB, _, _>(
+                        mapper, virt, frame.start_address(), flags, frame_allocator)?;
+                }
+                offset += page_size;
+                continue;
+            }
+        }
+        if page_size >= Size2MiB::SIZE {
+            let frame = <A as FrameAllocator<Size2MiB>>::allocate_frame(frame_allocator);
+            if let Some(frame) = frame {
+                unsafe {
+                    map_page::<Size2MiB, _, _>(
+                        mapper, virt, frame.start_address(), flags, frame_allocator)?;
+                }
+                offset += Size2MiB::SIZE;
+                continue;
+            }
+        }
+        let frame = <A as FrameAllocator<Size4KiB>>::allocate_frame(frame_allocator)
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            map_page::<Size4KiB, _, _>(
+                mapper, virt, frame.start_address(), flags, frame_allocator)?;
+        }
+        offset += Size4KiB::SIZE;
+    }
+    Ok(())
+}
+
+/// Maps `size` bytes at `virt` to the physical range at `phys`, with the
+/// largest pages both addresses are aligned for.
+///
+/// Unsafe because the caller must make sure the physical range may be
+/// accessed through the new mapping.
+pub unsafe fn map_physical_range<M, A>(mapper: &mut M, frame_allocator: &mut A,
+                                       virt: VirtAddr, phys: PhysAddr, size: u64,
+                                       flags: PageTableFlags)
+    -> Result<(), MapToError>
+where
+    M: MapperAllSizes,
+    A: FrameAllocator<Size4KiB>,
+{
+    assert!(virt.as_u64() % Size4KiB::SIZE == 0 && phys.as_u64() % Size4KiB::SIZE == 0,
+            "unaligned range {:?} -> {:?}", virt, phys);
+    let size = (size + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1);
 
-    // calculate the physical address by adding the page offset
-    Some(frame.start_address() + u64::from(addr.page_offset()))
-}
\ No newline at end of file
+    let mut offset = 0;
+    while offset < size {
+        let virt = virt.as_u64() + offset;
+        let phys = phys + offset;
+        let page_size = largest_page_size(virt, Some(phys.as_u64()), size - offset);
+        match page_size {
+            s if s == Size1GiB::SIZE => {
+                map_page::<Size1GiB, _, _>(mapper, virt, phys, flags, frame_allocator)?
+            }
+            s if s == Size2MiB::SIZE => {
+                map_page::<Size2MiB, _, _>(mapper, virt, phys, flags, frame_allocator)?
+            }
+            _ => map_page::<Size4KiB, _, _>(mapper, virt, phys, flags, frame_allocator)?,
+        }
+        offset += page_size;
+    }
+    Ok(())
+}
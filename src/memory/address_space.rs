@@ -0,0 +1,271 @@
+// Address spaces
+//
+// Every address space has a P4 table of its own. Its entries for the user
+// region belong to it alone, all others are copies of the kernel's P4
+// entries, so the kernel half and everything below it is shared. When the
+// CPU has PCIDs, every space gets one and switching keeps the TLB entries
+// of the others.
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Once;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, MapperAllSizes, OffsetPageTable, PageTable,
+    PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::unmap::{unmap_range, UnmapStats};
+use super::vmalloc::{VmError, REGIONS, USER_REGION};
+use super::walk::table_at;
+use super::{kernel_level_4_frame, physical_memory_offset, FRAME_ALLOCATOR};
+use crate::spinlock::IrqSpinLock;
+
+const PCID_COUNT: usize = 4096;
+// Set in CR3 to keep the TLB entries tagged with the new PCID
+const CR3_NO_FLUSH: u64 = 1 << 63;
+const CR4_PCIDE: u64 = 1 << 17;
+
+// Bumped whenever kernel mappings are removed. TLB entries of other PCIDs
+// may still hold them, so a space that last ran before has to be flushed.
+static KERNEL_GENERATION: AtomicU64 = AtomicU64::new(0);
+// Generation a space's TLB entries are from, forces a flush when stored
+const STALE: u64 = u64::max_value();
+// Generation of the kernel's own table (PCID 0)
+static KERNEL_FLUSHED: AtomicU64 = AtomicU64::new(STALE);
+
+// Bit n is set when PCID n is taken. PCID 0 is the kernel's.
+static PCIDS: IrqSpinLock<[u64; PCID_COUNT / 64]> = IrqSpinLock::new("pcids", [0; PCID_COUNT / 64]);
+
+static PCID: Once<bool> = Once::new();
+
+unsafe fn read_cr3() -> u64 {
+    let value: u64;
+    asm!("mov %cr3, $0" : "=r"(value));
+    value
+}
+
+unsafe fn write_cr3(value: u64) {
+    asm!("mov $0, %cr3" :: "r"(value) : "memory" : "volatile");
+}
+
+/// Flushes the TLB entries of the active PCID, like
+/// `x86_64::instructions::tlb::flush_all`. That one drops the PCID bits
+/// when it reloads CR3, which would switch the active space to PCID 0.
+pub fn flush_all() {
+    unsafe { write_cr3(read_cr3() & !CR3_NO_FLUSH) };
+}
+
+/// Whether address spaces get a PCID. Turns PCIDs on the first time it is
+/// called, if the CPU supports them.
+pub fn pcid_enabled() -> bool {
+    *PCID.call_once(|| {
+        use core::arch::x86_64::__cpuid;
+
+        let supported = unsafe { __cpuid(1) }.ecx & (1 << 17) != 0;
+        // CR4.PCIDE can only be set while the PCID bits of CR3 are 0
+        if !supported || unsafe { read_cr3() } & 0xfff != 0 {
+            return false;
+        }
+        unsafe {
+            let cr4: u64;
+            asm!("mov %cr4, $0" : "=r"(cr4));
+            asm!("mov $0, %cr4" :: "r"(cr4 | CR4_PCIDE) : "memory" : "volatile");
+        }
+        true
+    })
+}
+
+fn allocate_pcid() -> Option<u16> {
+    if !pcid_enabled() {
+        return None;
+    }
+    let mut used = PCIDS.lock();
+    let pcid = (1..PCID_COUNT).find(|&pcid| used[pcid / 64] & (1 << (pcid % 64)) == 0)?;
+    used[pcid / 64] |= 1 << (pcid % 64);
+    Some(pcid as u16)
+}
+
+fn free_pcid(pcid: u16) {
+    let pcid = pcid as usize;
+    PCIDS.lock()[pcid / 64] &= !(1 << (pcid % 64));
+}
+
+/// Called after kernel mappings were removed
+pub(crate) fn kernel_mappings_removed() {
+    KERNEL_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+// Loads `frame` into CR3. Keeps the PCID's TLB entries if they are from
+// the current generation.
+unsafe fn switch_to(frame: PhysFrame, pcid: Option<u16>, flushed: &AtomicU64) {
+    let generation = KERNEL_GENERATION.load(Ordering::Relaxed);
+    let mut value = frame.start_address().as_u64();
+    if let Some(pcid) = pcid {
+        value |= u64::from(pcid);
+        if flushed.swap(generation, Ordering::Relaxed) == generation {
+            value |= CR3_NO_FLUSH;
+        }
+    }
+    write_cr3(value);
+}
+
+/// Switches back to the kernel's own page tables
+pub unsafe fn activate_kernel() {
+    let pcid = if pcid_enabled() { Some(0) } else { None };
+    switch_to(kernel_level_4_frame(), pcid, &KERNEL_FLUSHED);
+}
+
+// P4 entries that belong to the user region
+fn user_entries() -> core::ops::Range<usize> {
+    let first = (USER_REGION.start >> 39) as usize;
+    first..first + (USER_REGION.size >> 39) as usize
+}
+
+static KERNEL_ENTRIES: AtomicBool = AtomicBool::new(false);
+
+/// Creates the kernel's P4 entries for every kernel region. Address spaces
+/// copy the kernel entries once, when they are created, so an entry made
+/// later would be missing from them. Must run before the first
+/// `AddressSpace::new`.
+pub fn init_kernel_entries(frames: &mut impl FrameAllocator<Size4KiB>)
+    -> Result<(), VmError> {
+    let kernel = unsafe { table_at(kernel_level_4_frame()) };
+    for region in REGIONS.iter().filter(|region| **region != USER_REGION) {
+        let first = (region.start >> 39) as usize & 0x1ff;
+        let last = ((region.end() - 1) >> 39) as usize & 0x1ff;
+        for index in first..=last {
+            if !kernel[index].is_unused() {
+                continue;
+            }
+            let frame = frames.allocate_frame().ok_or(VmError::OutOfMemory)?;
+            for entry in unsafe { table_at(frame) }.iter_mut() {
+                entry.set_unused();
+            }
+            kernel[index].set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+    KERNEL_ENTRIES.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    pcid: Option<u16>,
+    flushed: AtomicU64,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user region
+    pub fn new() -> Result<Self, VmError> {
+        assert!(KERNEL_ENTRIES.load(Ordering::Relaxed), "kernel P4 entries aren't set up");
+        let frame = {
+            let mut frames = FRAME_ALLOCATOR.lock();
+            let frames = frames.as_mut().ok_or(VmError::NotInitialized)?;
+            FrameAllocator::<Size4KiB>::allocate_frame(frames).ok_or(VmError::OutOfMemory)?
+        };
+        let mut space = AddressSpace {
+            level_4_frame: frame,
+            pcid: allocate_pcid(),
+            flushed: AtomicU64::new(STALE),
+        };
+        for entry in space.table().iter_mut() {
+            entry.set_unused();
+        }
+        space.copy_kernel_entries();
+        Ok(space)
+    }
+
+    fn table(&mut self) -> &mut PageTable {
+        unsafe { table_at(self.level_4_frame) }
+    }
+
+    // Copies the kernel's P4 entries, except the user region. They don't
+    // change after init_kernel_entries, only the tables below them do.
+    fn copy_kernel_entries(&self) {
+        let kernel = unsafe { table_at(kernel_level_4_frame()) };
+        let table = unsafe { table_at(self.level_4_frame) };
+        for index in (0..512).filter(|index| !user_entries().contains(index)) {
+            table[index] = kernel[index].clone();
+        }
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    pub fn pcid(&self) -> Option<u16> {
+        self.pcid
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Switches to this address space.
+    ///
+    /// Unsafe because references into the user region of the previous
+    /// address space become dangling.
+    pub unsafe fn activate(&self) {
+        switch_to(self.level_4_frame, self.pcid, &self.flushed);
+    }
+
+    /// Mapper for this address space's tables. Only touch the user region
+    /// with it, the rest is shared.
+    pub fn mapper(&mut self) -> OffsetPageTable {
+        let offset = VirtAddr::new(physical_memory_offset());
+        unsafe { OffsetPageTable::new(self.table(), offset) }
+    }
+
+    /// Maps `size` bytes of fresh memory at `start`, which has to lie in
+    /// the user region
+    pub fn map(&mut self, start: VirtAddr, size: u64, flags: PageTableFlags)
+        -> Result<(), VmError> {
+        check_user_range(start, size)?;
+        let mut frames = FRAME_ALLOCATOR.lock();
+        let frames = frames.as_mut().ok_or(VmError::NotInitialized)?;
+        super::map_range(&mut self.mapper(), frames, start, size, flags | PageTableFlags::PRESENT)?;
+        Ok(())
+    }
+
+    /// Unmaps part of the user region and frees the memory behind it
+    pub fn unmap(&mut self, start: VirtAddr, size: u64) -> Result<UnmapStats, VmError> {
+        check_user_range(start, size)?;
+        if !self.is_active() {
+            // The TLB may still hold the pages under our PCID
+            self.flushed.store(STALE, Ordering::Relaxed);
+        }
+        let mut frames = FRAME_ALLOCATOR.lock();
+        let frames = frames.as_mut().ok_or(VmError::NotInitialized)?;
+        Ok(unmap_range(&mut self.mapper(), frames, start, size, true)?)
+    }
+
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper().translate_addr(addr)
+    }
+}
+
+fn check_user_range(start: VirtAddr, size: u64) -> Result<(), VmError> {
+    if USER_REGION.contains(start) && start.as_u64() + size <= USER_REGION.end() {
+        Ok(())
+    } else {
+        Err(VmError::OutsideRegion(start))
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            unsafe { activate_kernel() };
+        }
+        let mut frames = FRAME_ALLOCATOR.lock();
+        if let Some(frames) = frames.as_mut() {
+            let user = VirtAddr::new(USER_REGION.start);
+            unmap_range(&mut self.mapper(), frames, user, USER_REGION.size, true)
+                .expect("failed to tear down the user region");
+            FrameDeallocator::<Size4KiB>::deallocate_frame(frames, self.level_4_frame);
+        }
+        if let Some(pcid) = self.pcid {
+            free_pcid(pcid);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+// Bitmap physical frame allocator
+//
+// One bit per 4 KiB frame up to the end of the highest usable region, set
+// while the frame is in use. The bitmap itself lives in the first usable
+// region large enough to hold it.
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::slice;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+};
+use x86_64::PhysAddr;
+
+const FRAME_SIZE: u64 = 4096;
+const BITS: usize = 64;
+
+/// Frame counts of a frame allocator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Usable frames, including the ones holding allocator metadata
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    // Usable frames in the memory map
+    total: usize,
+    free: usize,
+    // Word to start the next search at; every word before it is full
+    next: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Builds the bitmap from the usable regions of `memory_map`.
+    ///
+    /// Unsafe because the caller must guarantee that the usable regions
+    /// really are unused, and that all physical memory is mapped at
+    /// `physical_memory_offset`.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: u64)
+        -> Self {
+        let usable = || memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| r.range.start_addr()..r.range.end_addr());
+
+        let end = usable().map(|r| r.end).max().unwrap_or(0);
+        let frames = (end / FRAME_SIZE) as usize;
+        let words = (frames + BITS - 1) / BITS;
+        let bitmap_size = (words * 8) as u64;
+        let bitmap_frames = (bitmap_size + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        let bitmap_start = usable()
+            .find(|r| r.end - r.start >= bitmap_frames * FRAME_SIZE)
+            .map(|r| r.start)
+            .expect("no usable region can hold the frame bitmap");
+        let bitmap = slice::from_raw_parts_mut(
+            (bitmap_start + physical_memory_offset) as *mut u64, words);
+
+        // Everything that isn't usable memory stays marked as used
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            total: 0,
+            free: 0,
+            next: 0,
+        };
+        for range in usable() {
+            for frame in range.start / FRAME_SIZE..range.end / FRAME_SIZE {
+                allocator.set_used(frame as usize, false);
+                allocator.total += 1;
+                allocator.free += 1;
+            }
+        }
+
+        let bitmap_first = (bitmap_start / FRAME_SIZE) as usize;
+        for frame in bitmap_first..bitmap_first + bitmap_frames as usize {
+            allocator.set_used(frame, true);
+            allocator.free -= 1;
+        }
+        // Keep frame 0 out of circulation, so a physical address of zero
+        // never is a valid allocation
+        if !allocator.is_used(0) {
+            allocator.set_used(0, true);
+            allocator.free -= 1;
+        }
+        allocator
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total,
+            used: self.total - self.free,
+            free: self.free,
+        }
+    }
+
+    /// Takes `frame` out of the free frames if it is free. For handing
+    /// specific frames to another allocator.
+    pub fn claim_frame(&mut self, frame: PhysFrame) -> bool {
+        let index = frame_index(frame);
+        if self.is_used(index) {
+            return false;
+        }
+        self.set_used(index, true);
+        self.free -= 1;
+        true
+    }
+
+    /// Whether `frame` is allocated or not usable at all
+    pub fn is_allocated(&self, frame: PhysFrame) -> bool {
+        self.is_used(frame_index(frame))
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        match self.bitmap.get(index / BITS) {
+            Some(word) => word & (1 << (index % BITS)) != 0,
+            None => true,
+        }
+    }
+
+    fn set_used(&mut self, index: usize, used: bool) {
+        let word = &mut self.bitmap[index / BITS];
+        if used {
+            *word |= 1 << (index % BITS);
+        } else {
+            *word &= !(1 << (index % BITS));
+        }
+    }
+}
+
+impl BitmapFrameAllocator {
+    // Allocates `words * 64` contiguous frames, starting at a multiple of
+    // `align_words * 64` frames. Huge frames are always multiples of 64
+    // frames, so whole words can be checked at once.
+    fn allocate_words(&mut self, words: usize, align_words: usize) -> Option<usize> {
+        if self.free < words * BITS {
+            return None;
+        }
+        let start = (self.next + align_words - 1) / align_words * align_words;
+        let word = (start..self.bitmap.len())
+            .step_by(align_words)
+            .take_while(|&word| word + words <= self.bitmap.len())
+            .find(|&word| self.bitmap[word..word + words].iter().all(|&w| w == 0))?;
+        for w in &mut self.bitmap[word..word + words] {
+            *w = !0;
+        }
+        self.free -= words * BITS;
+        Some(word * BITS)
+    }
+
+    fn free_words(&mut self, index: usize, words: usize) {
+        let word = index / BITS;
+        for w in &mut self.bitmap[word..word + words] {
+            assert_eq!(*w, !0, "double free of frames at index {}", index);
+            *w = 0;
+        }
+        self.free += words * BITS;
+        self.next = self.next.min(word);
+    }
+}
+
+fn frame_index(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free == 0 {
+            return None;
+        }
+        // Full words are skipped for good, so this is O(1) amortised
+        while self.bitmap[self.next] == !0 {
+            self.next += 1;
+        }
+        let index = self.next * BITS + (!self.bitmap[self.next]).trailing_zeros() as usize;
+        self.set_used(index, true);
+        self.free -= 1;
+        let address = PhysAddr::new(index as u64 * FRAME_SIZE);
+        Some(PhysFrame::containing_address(address))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = frame_index(frame);
+        assert!(self.is_used(index), "double free of {:?}", frame);
+        self.set_used(index, false);
+        self.free += 1;
+        self.next = self.next.min(index / BITS);
+    }
+}
+
+// Frames per huge frame, in bitmap words
+fn huge_words<S: PageSize>() -> usize {
+    (S::SIZE / FRAME_SIZE) as usize / BITS
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let words = huge_words::<Size2MiB>();
+        let index = self.allocate_words(words, words)?;
+        let address = PhysAddr::new(index as u64 * FRAME_SIZE);
+        Some(PhysFrame::containing_address(address))
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        let words = huge_words::<Size1GiB>();
+        let index = self.allocate_words(words, words)?;
+        let address = PhysAddr::new(index as u64 * FRAME_SIZE);
+        Some(PhysFrame::containing_address(address))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        self.free_words(index, huge_words::<Size2MiB>());
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BitmapFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        self.free_words(index, huge_words::<Size1GiB>());
+    }
+}
@@ -0,0 +1,234 @@
+// Buddy allocator for physically contiguous memory
+//
+// Manages a pool of frames taken from the bitmap allocator in blocks of
+// 2^order frames. Free blocks are kept in one list per order, linked
+// through their first word, so the allocator needs no memory of its own.
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+use super::bitmap::{BitmapFrameAllocator, FrameStats};
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Number of block sizes, the largest being 2^(ORDERS - 1) frames (4 MiB)
+pub const ORDERS: usize = 11;
+
+/// Where an allocation may be placed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Constraints {
+    /// The whole block must end at or below this address
+    pub max_address: u64,
+    /// Alignment of the block start in bytes, a power of two
+    pub align: u64,
+}
+
+impl Constraints {
+    pub const NONE: Constraints = Constraints {
+        max_address: u64::max_value(),
+        align: FRAME_SIZE,
+    };
+    /// For real mode code and ISA DMA
+    pub const BELOW_1MIB: Constraints = Constraints {
+        max_address: 0x10_0000,
+        ..Constraints::NONE
+    };
+    /// For devices with 32 bit DMA addresses
+    pub const BELOW_4GIB: Constraints = Constraints {
+        max_address: 0x1_0000_0000,
+        ..Constraints::NONE
+    };
+
+    pub fn aligned(self, align: u64) -> Constraints {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        Constraints { align, ..self }
+    }
+}
+
+pub struct BuddyAllocator {
+    // Physical address of the first free block of each order, 0 if none.
+    // Frame 0 is never handed to the pool, so 0 can't be a block.
+    free_lists: [u64; ORDERS],
+    total: usize,
+    free: usize,
+    physical_memory_offset: u64,
+}
+
+fn block_size(order: usize) -> u64 {
+    FRAME_SIZE << order
+}
+
+/// Smallest order whose blocks hold `frames` frames
+pub fn order_for(frames: usize) -> usize {
+    frames.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+impl BuddyAllocator {
+    pub const fn empty(physical_memory_offset: u64) -> Self {
+        BuddyAllocator {
+            free_lists: [0; ORDERS],
+            total: 0,
+            free: 0,
+            physical_memory_offset,
+        }
+    }
+
+    /// Builds a pool of up to `pool_size` bytes out of the usable memory
+    /// that `frames` hasn't handed out yet. Low addresses come first, so
+    /// the pool gets the memory below 1 MiB.
+    ///
+    /// Unsafe for the same reasons as `BitmapFrameAllocator::init`.
+    pub unsafe fn init(
+        memory_map: &'static MemoryMap,
+        frames: &mut BitmapFrameAllocator,
+        physical_memory_offset: u64,
+        pool_size: u64,
+    ) -> Self {
+        let mut allocator = BuddyAllocator::empty(physical_memory_offset);
+        let mut budget = pool_size / FRAME_SIZE;
+
+        let usable = memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+        for region in usable {
+            // Start of the current run of claimed frames
+            let mut run_start = None;
+            let mut address = region.range.start_addr();
+            while address < region.range.end_addr() && budget > 0 {
+                let frame = PhysFrame::containing_address(PhysAddr::new(address));
+                if frames.claim_frame(frame) {
+                    run_start = run_start.or(Some(address));
+                    budget -= 1;
+                } else if let Some(start) = run_start.take() {
+                    allocator.add_region(start, address);
+                }
+                address += FRAME_SIZE;
+            }
+            if let Some(start) = run_start {
+                allocator.add_region(start, address);
+            }
+        }
+        allocator
+    }
+
+    /// Hands the frames in `start..end` to the allocator
+    pub unsafe fn add_region(&mut self, start: u64, end: u64) {
+        let mut start = (start + FRAME_SIZE - 1) & !(FRAME_SIZE - 1);
+        let end = end & !(FRAME_SIZE - 1);
+        while start < end {
+            // Largest block that is aligned and fits
+            let order = (0..ORDERS)
+                .rev()
+                .find(|&order| {
+                    start % block_size(order) == 0 && start + block_size(order) <= end
+                })
+                .unwrap();
+            self.total += 1 << order;
+            self.free_block(start, order);
+            start += block_size(order);
+        }
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total,
+            used: self.total - self.free,
+            free: self.free,
+        }
+    }
+
+    /// Allocates 2^order contiguous frames that satisfy `constraints`.
+    /// Returns the first frame.
+    pub fn allocate(&mut self, order: usize, constraints: Constraints) -> Option<PhysFrame> {
+        assert!(order < ORDERS, "order {} too large", order);
+        // Blocks are aligned to their size, so a large enough block is
+        // aligned well enough
+        let min_order = order.max(order_for((constraints.align / FRAME_SIZE) as usize));
+
+        for current in min_order..ORDERS {
+            let fits = |block: u64| block + block_size(order) <= constraints.max_address;
+            let block = match self.find_block(current, fits) {
+                Some(block) => block,
+                None => continue,
+            };
+            self.remove_block(block, current);
+
+            // Keep the lower half and free the upper half until the block
+            // has the requested size
+            let mut split = current;
+            while split > order {
+                split -= 1;
+                self.push_block(block + block_size(split), split);
+            }
+            self.free -= 1 << order;
+            return Some(PhysFrame::containing_address(PhysAddr::new(block)));
+        }
+        None
+    }
+
+    /// Returns a block from `allocate`, merging it with its free buddies
+    pub fn deallocate(&mut self, frame: PhysFrame, order: usize) {
+        let start = frame.start_address().as_u64();
+        assert!(start % block_size(order) == 0, "{:?} is not an order {} block", frame, order);
+        debug_assert!(!self.is_free(start, order), "double free of {:?}", frame);
+        unsafe { self.free_block(start, order) };
+    }
+
+    // Whether the block is on its free list, or part of a larger free block
+    // after merging
+    fn is_free(&self, start: u64, order: usize) -> bool {
+        (order..ORDERS).any(|order| {
+            let containing = start & !(block_size(order) - 1);
+            self.find_block(order, |block| block == containing).is_some()
+        })
+    }
+
+    unsafe fn free_block(&mut self, mut start: u64, mut order: usize) {
+        self.free += 1 << order;
+        while order + 1 < ORDERS {
+            let buddy = start ^ block_size(order);
+            if !self.remove_block(buddy, order) {
+                break;
+            }
+            start = start.min(buddy);
+            order += 1;
+        }
+        self.push_block(start, order);
+    }
+
+    fn next_pointer(&self, block: u64) -> *mut u64 {
+        (block + self.physical_memory_offset) as *mut u64
+    }
+
+    fn push_block(&mut self, block: u64, order: usize) {
+        unsafe { self.next_pointer(block).write(self.free_lists[order]) };
+        self.free_lists[order] = block;
+    }
+
+    fn find_block(&self, order: usize, mut predicate: impl FnMut(u64) -> bool) -> Option<u64> {
+        let mut block = self.free_lists[order];
+        while block != 0 {
+            if predicate(block) {
+                return Some(block);
+            }
+            block = unsafe { self.next_pointer(block).read() };
+        }
+        None
+    }
+
+    // Unlinks `block` from the list of `order`, returns false if it isn't
+    // in there
+    fn remove_block(&mut self, block: u64, order: usize) -> bool {
+        let mut link: *mut u64 = &mut self.free_lists[order];
+        unsafe {
+            while *link != 0 {
+                if *link == block {
+                    *link = self.next_pointer(block).read();
+                    return true;
+                }
+                link = self.next_pointer(*link);
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,210 @@
+// Page table dumps
+//
+// Walks a whole set of page tables with the same walker as
+// walk_page_tables, and merges neighbouring pages that map contiguous
+// physical memory with the same flags into one range.
+use core::fmt;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::vmalloc::USER_REGION;
+use super::walk::{self, entry_size, table_at, Visitor};
+
+// Flags that matter for a mapping. ACCESSED and DIRTY would keep ranges
+// from merging, and bit 7 means PAT at P1.
+fn shown_flags() -> PageTableFlags {
+    PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::GLOBAL
+        | PageTableFlags::NO_EXECUTE
+}
+
+/// A range of virtual memory mapped to contiguous physical memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub virt: VirtAddr,
+    pub phys: PhysAddr,
+    pub size: u64,
+    /// Flags of the leaf entries, with WRITABLE and USER_ACCESSIBLE cleared
+    /// and NO_EXECUTE set if a parent entry says so
+    pub flags: PageTableFlags,
+}
+
+impl Mapping {
+    /// End of the range, 0 if it reaches the top of the address space
+    pub fn end(&self) -> u64 {
+        self.virt.as_u64().wrapping_add(self.size)
+    }
+
+    pub fn writable(&self) -> bool {
+        self.flags.contains(PageTableFlags::WRITABLE)
+    }
+
+    pub fn executable(&self) -> bool {
+        !self.flags.contains(PageTableFlags::NO_EXECUTE)
+    }
+
+    pub fn user(&self) -> bool {
+        self.flags.contains(PageTableFlags::USER_ACCESSIBLE)
+    }
+
+    // Whether `next` continues this mapping
+    fn continues_with(&self, next: &Mapping) -> bool {
+        self.flags == next.flags
+            && self.end() == next.virt.as_u64()
+            && self.phys + self.size == next.phys
+    }
+}
+
+/// One line of the dump
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}-{:#018x} {:#012x}-{:#012x} {} {}",
+               self.virt.as_u64(), self.end().wrapping_sub(1),
+               self.phys.as_u64(), (self.phys + self.size).as_u64() - 1,
+               FlagString(self.flags), Size(self.size))
+    }
+}
+
+// Flags of an entry as they apply, given the effective flags of its parent
+fn effective_flags(parent: PageTableFlags, flags: PageTableFlags) -> PageTableFlags {
+    let mut effective = flags & shown_flags();
+    for &inherited in [PageTableFlags::WRITABLE, PageTableFlags::USER_ACCESSIBLE].iter() {
+        if !parent.contains(inherited) {
+            effective.remove(inherited);
+        }
+    }
+    if parent.contains(PageTableFlags::NO_EXECUTE) {
+        effective.insert(PageTableFlags::NO_EXECUTE);
+    }
+    effective
+}
+
+struct PageWalk<F> {
+    // Effective flags of the entry above the table at each level
+    parents: [PageTableFlags; 5],
+    visit: F,
+}
+
+impl<F: FnMut(Mapping)> Visitor for PageWalk<F> {
+    type Error = ();
+
+    fn leaf(&mut self, entry: &mut PageTableEntry, level: u8, start: u64) -> Result<(), ()> {
+        (self.visit)(Mapping {
+            virt: VirtAddr::new(start),
+            phys: entry.addr(),
+            size: entry_size(level),
+            flags: effective_flags(self.parents[level as usize], entry.flags()),
+        });
+        Ok(())
+    }
+
+    fn enter(&mut self, entry: &PageTableEntry, level: u8, _start: u64) -> bool {
+        let level = level as usize;
+        self.parents[level - 1] = effective_flags(self.parents[level], entry.flags());
+        true
+    }
+}
+
+/// Calls `visit` for every mapped page of the tables at `level_4_frame`,
+/// in address order
+pub fn for_each_page(level_4_frame: PhysFrame, visit: impl FnMut(Mapping)) {
+    let root = PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE;
+    let mut pages = PageWalk { parents: [root; 5], visit };
+    let table = unsafe { table_at(level_4_frame) };
+    let _ = walk::walk(table, 0, u64::max_value(), &mut pages);
+}
+
+/// Like `for_each_page`, but merges pages that continue each other
+pub fn for_each_range(level_4_frame: PhysFrame, mut visit: impl FnMut(Mapping)) {
+    let mut current: Option<Mapping> = None;
+    for_each_page(level_4_frame, |page| {
+        if let Some(range) = current.as_mut() {
+            if range.continues_with(&page) {
+                range.size += page.size;
+                return;
+            }
+        }
+        if let Some(range) = current.replace(page) {
+            visit(range);
+        }
+    });
+    if let Some(range) = current {
+        visit(range);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// Writable and executable at the same time
+    WritableExecutable(Mapping),
+    /// Accessible from user mode outside the user region
+    UserOutsideUserRegion(Mapping),
+}
+
+/// Checks every range against the rules for kernel mappings. Calls
+/// `report` for each violation and returns how many there were.
+pub fn check(level_4_frame: PhysFrame, mut report: impl FnMut(Violation)) -> usize {
+    let mut count = 0;
+    for_each_range(level_4_frame, |range| {
+        if range.writable() && range.executable() {
+            report(Violation::WritableExecutable(range));
+            count += 1;
+        }
+        let in_user_region = USER_REGION.contains(range.virt)
+            && range.end() <= USER_REGION.end();
+        if range.user() && !in_user_region {
+            report(Violation::UserOutsideUserRegion(range));
+            count += 1;
+        }
+    });
+    count
+}
+
+// 4K, 2M, 1G, or bytes if it isn't a whole number of any
+struct Size(u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let units = [(1 << 30, "G"), (1 << 20, "M"), (1 << 10, "K")];
+        match units.iter().find(|&&(unit, _)| self.0 >= unit && self.0 % unit == 0) {
+            Some(&(unit, suffix)) => write!(f, "{}{}", self.0 / unit, suffix),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// Short form of the flags: w(ritable), x (executable), u(ser), g(lobal),
+/// c (cache disabled), t (write-through), '-' where not set
+pub struct FlagString(pub PageTableFlags);
+
+impl fmt::Display for FlagString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = self.0;
+        let show = |set: bool, c: char| if set { c } else { '-' };
+        write!(f, "{}{}{}{}{}{}",
+               show(flags.contains(PageTableFlags::WRITABLE), 'w'),
+               show(!flags.contains(PageTableFlags::NO_EXECUTE), 'x'),
+               show(flags.contains(PageTableFlags::USER_ACCESSIBLE), 'u'),
+               show(flags.contains(PageTableFlags::GLOBAL), 'g'),
+               show(flags.contains(PageTableFlags::NO_CACHE), 'c'),
+               show(flags.contains(PageTableFlags::WRITE_THROUGH), 't'))
+    }
+}
+
+/// Writes one line per mapped range
+pub fn dump(level_4_frame: PhysFrame, output: &mut impl fmt::Write) -> fmt::Result {
+    writeln!(output, "{:<37} {:<25} flags  size", "virtual", "physical")?;
+    let mut result = Ok(());
+    for_each_range(level_4_frame, |range| {
+        if result.is_ok() {
+            result = writeln!(output, "{}", range);
+        }
+    });
+    result
+}
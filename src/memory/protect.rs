@@ -0,0 +1,156 @@
+// W^X for the kernel image
+//
+// The linker maps the ELF header at __ehdr_start, in the first loadable
+// segment, so the program headers of the running kernel can be read back.
+// Every page then gets exactly the permissions of its segment: code is
+// read-only and executable, read-only data and writable data are
+// no-execute. Everything else the bootloader mapped, like the stack and
+// the physical memory window with its writable alias of the code, becomes
+// no-execute too.
+use x86_64::registers::control::{Cr0, Cr0Flags, Efer, EferFlags};
+use x86_64::structures::paging::mapper::FlagUpdateError;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::walk::{self, entry_size, table_at, Visitor};
+
+extern "C" {
+    static __ehdr_start: u8;
+}
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// A loadable segment of the kernel image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl Segment {
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let first = Page::containing_address(self.start);
+        let last = Page::containing_address(self.end - 1u64);
+        Page::range_inclusive(first, last)
+    }
+
+    fn contains(&self, page: Page) -> bool {
+        self.overlaps(page.start_address().as_u64(), 4096)
+    }
+
+    fn overlaps(&self, start: u64, size: u64) -> bool {
+        start < self.end.as_u64() && self.start.as_u64() < start.saturating_add(size)
+    }
+}
+
+unsafe fn read<T: Copy>(addr: *const u8, offset: usize) -> T {
+    (addr.add(offset) as *const T).read_unaligned()
+}
+
+/// The loadable segments of the running kernel
+pub fn kernel_segments() -> impl Iterator<Item = Segment> {
+    let header = unsafe { &__ehdr_start as *const u8 };
+    let magic = unsafe { core::slice::from_raw_parts(header, 4) };
+    assert_eq!(magic, ELF_MAGIC, "no ELF header at __ehdr_start");
+
+    let (program_headers, entry_size, count) = unsafe {
+        (read::<u64>(header, 32) as usize, read::<u16>(header, 54) as usize,
+         read::<u16>(header, 56) as usize)
+    };
+    (0..count)
+        .map(move |i| unsafe { header.add(program_headers + i * entry_size) })
+        .filter(|&entry| unsafe { read::<u32>(entry, 0) } == PT_LOAD)
+        .filter_map(|entry| {
+            let (flags, vaddr, size) = unsafe {
+                (read::<u32>(entry, 4), read::<u64>(entry, 16), read::<u64>(entry, 40))
+            };
+            if size == 0 {
+                return None;
+            }
+            Some(Segment {
+                start: VirtAddr::new(vaddr),
+                end: VirtAddr::new(vaddr + size),
+                writable: flags & PF_W != 0,
+                executable: flags & PF_X != 0,
+            })
+        })
+}
+
+/// Whether the CPU has the NX bit. Without it, setting EFER.NXE faults.
+pub fn nx_supported() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended_leaf >= 0x8000_0001
+        && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 20) != 0
+}
+
+/// NO_EXECUTE if the CPU enforces it. Setting the bit before EFER.NXE is
+/// on makes the entry invalid.
+pub fn no_execute() -> PageTableFlags {
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+// Flags for a page of the image. Segments don't share pages, but if they
+// did the page would need the permissions of both.
+fn page_flags(page: Page) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT | no_execute();
+    for segment in kernel_segments().filter(|segment| segment.contains(page)) {
+        if segment.writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if segment.executable {
+            flags.remove(PageTableFlags::NO_EXECUTE);
+        }
+    }
+    flags
+}
+
+// Makes every page outside the code segments no-execute
+struct NoExecute;
+
+impl Visitor for NoExecute {
+    type Error = ();
+
+    fn leaf(&mut self, entry: &mut PageTableEntry, level: u8, start: u64) -> Result<(), ()> {
+        let size = entry_size(level);
+        if !kernel_segments().any(|segment| segment.executable && segment.overlaps(start, size)) {
+            entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+        }
+        Ok(())
+    }
+}
+
+/// Turns on NX, if the CPU has it, and supervisor write protection. Then
+/// remaps the kernel image so no page is both writable and executable, and
+/// makes everything else in the kernel's tables no-execute.
+pub fn protect_kernel(mapper: &mut impl Mapper<Size4KiB>) -> Result<(), FlagUpdateError> {
+    unsafe {
+        if nx_supported() {
+            Efer::write(Efer::read() | EferFlags::NO_EXECUTE_ENABLE);
+        }
+        Cr0::write(Cr0::read() | Cr0Flags::WRITE_PROTECT);
+    }
+    for segment in kernel_segments() {
+        for page in segment.pages() {
+            mapper.update_flags(page, page_flags(page))?.flush();
+        }
+    }
+    if no_execute().is_empty() {
+        return Ok(());
+    }
+    let table = unsafe { table_at(super::kernel_level_4_frame()) };
+    let _ = walk::walk(table, 0, u64::max_value(), &mut NoExecute);
+    super::address_space::flush_all();
+    Ok(())
+}
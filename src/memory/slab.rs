@@ -0,0 +1,359 @@
+// Slab allocator
+//
+// A cache hands out objects of one size. Its memory comes in slabs of one
+// frame each, reached through the physical memory mapping. Every slab
+// starts with a header and is cut into objects after it. Slabs are kept on
+// a full, a partial or an empty list, so allocating only has to look at
+// the first partial slab, and freeing finds the slab by rounding down.
+use core::mem::{align_of, size_of};
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+use super::{physical_memory_offset, FRAME_ALLOCATOR};
+use crate::spinlock::IrqSpinLock;
+
+const SLAB_SIZE: usize = 4096;
+// Empty slabs a cache keeps around before giving frames back
+const KEEP_EMPTY: usize = 1;
+const MAX_CACHES: usize = 32;
+
+struct Slab {
+    cache: *const SlabCache,
+    next: *mut Slab,
+    prev: *mut Slab,
+    // First free object, the rest are linked from it
+    free: *mut u8,
+    in_use: usize,
+}
+
+struct SlabList {
+    head: *mut Slab,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        SlabList { head: null_mut(), len: 0 }
+    }
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.head;
+        if let Some(head) = self.head.as_mut() {
+            head.prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut Slab) {
+        let (prev, next) = ((*slab).prev, (*slab).next);
+        match prev.as_mut() {
+            Some(prev) => prev.next = next,
+            None => self.head = next,
+        }
+        if let Some(next) = next.as_mut() {
+            next.prev = prev;
+        }
+        self.len -= 1;
+    }
+
+    unsafe fn pop(&mut self) -> Option<*mut Slab> {
+        let slab = self.head;
+        if slab.is_null() {
+            return None;
+        }
+        self.remove(slab);
+        Some(slab)
+    }
+}
+
+struct Lists {
+    full: SlabList,
+    partial: SlabList,
+    empty: SlabList,
+    allocations: u64,
+    frees: u64,
+}
+
+// The slabs are only reachable through the cache
+unsafe impl Send for Lists {}
+
+/// Counters of a cache, for `slabinfo`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    /// Bytes between objects, which is at least the requested size
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub active_objects: usize,
+    pub total_objects: usize,
+    pub full_slabs: usize,
+    pub partial_slabs: usize,
+    pub empty_slabs: usize,
+    pub allocations: u64,
+    pub frees: u64,
+}
+
+/// A named cache of objects of one size. Meant to be a static:
+///
+/// ```ignore
+/// static TIMERS: SlabCache = SlabCache::new("timer", size_of::<Timer>(),
+///                                           align_of::<Timer>(), None);
+/// ```
+pub struct SlabCache {
+    name: &'static str,
+    size: usize,
+    align: usize,
+    constructor: Option<fn(*mut u8)>,
+    registered: AtomicBool,
+    lists: IrqSpinLock<Lists>,
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+impl SlabCache {
+    /// `constructor` runs once for every object when its slab is created,
+    /// not on every allocation. Objects should be freed in the state it
+    /// leaves them in.
+    pub const fn new(name: &'static str, size: usize, align: usize,
+                     constructor: Option<fn(*mut u8)>) -> Self {
+        SlabCache {
+            name,
+            size,
+            align,
+            constructor,
+            registered: AtomicBool::new(false),
+            lists: IrqSpinLock::new(name, Lists {
+                full: SlabList::new(),
+                partial: SlabList::new(),
+                empty: SlabList::new(),
+                allocations: 0,
+                frees: 0,
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // Free objects are linked through a pointer inside them. With a
+    // constructor the object has to stay intact, so it goes behind it.
+    fn link_offset(&self) -> usize {
+        match self.constructor {
+            Some(_) => align_up(self.size, align_of::<*mut u8>()),
+            None => 0,
+        }
+    }
+
+    fn stride(&self) -> usize {
+        let size = self.size.max(self.link_offset() + size_of::<*mut u8>());
+        align_up(size, self.align.max(align_of::<*mut u8>()))
+    }
+
+    fn first_object(&self) -> usize {
+        align_up(size_of::<Slab>(), self.align.max(align_of::<*mut u8>()))
+    }
+
+    pub fn objects_per_slab(&self) -> usize {
+        SLAB_SIZE.saturating_sub(self.first_object()) / self.stride()
+    }
+
+    unsafe fn link(&self, object: *mut u8) -> *mut *mut u8 {
+        object.add(self.link_offset()) as *mut *mut u8
+    }
+
+    /// Returns None if no frame can be found, even after shrinking the
+    /// other caches
+    pub fn allocate(&'static self) -> Option<NonNull<u8>> {
+        assert!(self.objects_per_slab() > 0, "objects of {} don't fit in a slab", self.name);
+        register(self);
+        loop {
+            if let Some(object) = unsafe { self.take(&mut self.lists.lock()) } {
+                return Some(object);
+            }
+            // Frames are allocated without holding the cache lock, another
+            // allocation may get to the new slab first
+            let slab = self.new_slab()?;
+            unsafe { self.lists.lock().empty.push(slab) };
+        }
+    }
+
+    unsafe fn take(&self, lists: &mut Lists) -> Option<NonNull<u8>> {
+        let slab = match lists.partial.head.as_mut() {
+            Some(slab) => slab as *mut Slab,
+            None => {
+                let slab = lists.empty.pop()?;
+                lists.partial.push(slab);
+                slab
+            }
+        };
+        let object = (*slab).free;
+        (*slab).free = *self.link(object);
+        (*slab).in_use += 1;
+        if (*slab).free.is_null() {
+            lists.partial.remove(slab);
+            lists.full.push(slab);
+        }
+        lists.allocations += 1;
+        NonNull::new(object)
+    }
+
+    /// Unsafe because `object` must come from `allocate` on this cache and
+    /// can't be used afterwards
+    pub unsafe fn deallocate(&self, object: NonNull<u8>) {
+        let object = object.as_ptr();
+        let slab = (object as usize & !(SLAB_SIZE - 1)) as *mut Slab;
+        assert_eq!((*slab).cache, self as *const SlabCache,
+                   "object freed to the wrong cache ({})", self.name);
+
+        let mut lists = self.lists.lock();
+        let was_full = (*slab).free.is_null();
+        *self.link(object) = (*slab).free;
+        (*slab).free = object;
+        (*slab).in_use -= 1;
+        lists.frees += 1;
+        if was_full {
+            lists.full.remove(slab);
+            lists.partial.push(slab);
+        }
+        if (*slab).in_use == 0 {
+            lists.partial.remove(slab);
+            lists.empty.push(slab);
+        }
+
+        if lists.empty.len > KEEP_EMPTY {
+            // Freeing may happen with the frame allocator held, trimming can
+            // wait until the next time then
+            if let Some(mut frames) = FRAME_ALLOCATOR.try_lock() {
+                if let Some(frames) = frames.as_mut() {
+                    while lists.empty.len > KEEP_EMPTY {
+                        let slab = lists.empty.pop().unwrap();
+                        FrameDeallocator::<Size4KiB>::deallocate_frame(frames, slab_frame(slab));
+                    }
+                }
+            }
+        }
+    }
+
+    fn new_slab(&self) -> Option<*mut Slab> {
+        let frame = match allocate_frame() {
+            Some(frame) => frame,
+            None => {
+                shrink_all();
+                allocate_frame()?
+            }
+        };
+        let start = frame.start_address().as_u64() + physical_memory_offset();
+        let slab = start as *mut Slab;
+        unsafe {
+            let mut free = null_mut();
+            for index in (0..self.objects_per_slab()).rev() {
+                let object = (start as usize + self.first_object() + index * self.stride()) as *mut u8;
+                if let Some(constructor) = self.constructor {
+                    constructor(object);
+                }
+                *self.link(object) = free;
+                free = object;
+            }
+            slab.write(Slab {
+                cache: self,
+                next: null_mut(),
+                prev: null_mut(),
+                free,
+                in_use: 0,
+            });
+        }
+        Some(slab)
+    }
+
+    /// Gives the frames of all empty slabs back. Returns how many, 0 if
+    /// the frame allocator is taken.
+    pub fn shrink(&self) -> usize {
+        let mut lists = self.lists.lock();
+        // The caller may hold the frame allocator, like in `deallocate`
+        let mut frames = match FRAME_ALLOCATOR.try_lock() {
+            Some(frames) => frames,
+            None => return 0,
+        };
+        let frames = match frames.as_mut() {
+            Some(frames) => frames,
+            None => return 0,
+        };
+        let mut freed = 0;
+        while let Some(slab) = unsafe { lists.empty.pop() } {
+            FrameDeallocator::<Size4KiB>::deallocate_frame(frames, slab_frame(slab));
+            freed += 1;
+        }
+        freed
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let lists = self.lists.lock();
+        let per_slab = self.objects_per_slab();
+        let mut partial_objects = 0;
+        let mut slab = lists.partial.head;
+        while let Some(current) = unsafe { slab.as_ref() } {
+            partial_objects += current.in_use;
+            slab = current.next;
+        }
+        let slabs = lists.full.len + lists.partial.len + lists.empty.len;
+        SlabStats {
+            object_size: self.stride(),
+            objects_per_slab: per_slab,
+            active_objects: lists.full.len * per_slab + partial_objects,
+            total_objects: slabs * per_slab,
+            full_slabs: lists.full.len,
+            partial_slabs: lists.partial.len,
+            empty_slabs: lists.empty.len,
+            allocations: lists.allocations,
+            frees: lists.frees,
+        }
+    }
+}
+
+fn allocate_frame() -> Option<PhysFrame> {
+    let mut frames = FRAME_ALLOCATOR.lock();
+    FrameAllocator::<Size4KiB>::allocate_frame(frames.as_mut()?)
+}
+
+fn slab_frame(slab: *mut Slab) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(slab as u64 - physical_memory_offset()))
+}
+
+// Caches that have allocated at least once, so they can be listed and
+// shrunk
+static CACHES: IrqSpinLock<[Option<&'static SlabCache>; MAX_CACHES]> =
+    IrqSpinLock::new("slab_caches", [None; MAX_CACHES]);
+
+fn register(cache: &'static SlabCache) {
+    if cache.registered.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let mut caches = CACHES.lock();
+    match caches.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(cache),
+        None => panic!("too many slab caches"),
+    }
+}
+
+pub fn for_each_cache(mut f: impl FnMut(&'static SlabCache)) {
+    // Copied so `f` may use the caches freely
+    let caches = *CACHES.lock();
+    for cache in caches.iter().filter_map(|cache| *cache) {
+        f(cache);
+    }
+}
+
+/// Gives the frames of every cache's empty slabs back. Caches, the heap and
+/// vmalloc do this when they run out of frames. Returns the number of
+/// frames.
+pub fn shrink_all() -> usize {
+    let mut freed = 0;
+    for_each_cache(|cache| freed += cache.shrink());
+    freed
+}
@@ -0,0 +1,164 @@
+// Unmapping ranges and reclaiming page tables
+//
+// Walks the page tables directly instead of going through Mapper::unmap
+// page by page, so huge pages are handled, page tables that end up empty
+// can be freed, and the TLB is flushed once per call.
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameDeallocator, OffsetPageTable, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+use super::vmalloc::USER_REGION;
+use super::walk::{self, entry_size, table_at, Visitor};
+
+// Up to this many pages get flushed one by one, more flush the whole TLB
+const MAX_SINGLE_FLUSHES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnmapRangeError {
+    /// The range covers only part of a huge page. Everything before it has
+    /// been unmapped already.
+    PartialHugePage(VirtAddr),
+}
+
+/// What `unmap_range` did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UnmapStats {
+    /// Mapped pages removed, huge pages count once
+    pub pages: usize,
+    /// Frames handed back to the frame allocator, in 4 KiB frames
+    pub frames_freed: usize,
+    /// Page tables freed because they became empty
+    pub tables_freed: usize,
+}
+
+// Pages waiting for a TLB flush
+struct TlbBatch {
+    pages: [u64; MAX_SINGLE_FLUSHES],
+    count: usize,
+}
+
+impl TlbBatch {
+    fn add(&mut self, addr: u64) {
+        if self.count < MAX_SINGLE_FLUSHES {
+            self.pages[self.count] = addr;
+        }
+        self.count += 1;
+    }
+
+    fn flush(&self) {
+        if self.count > MAX_SINGLE_FLUSHES {
+            super::address_space::flush_all();
+        } else {
+            for &addr in &self.pages[..self.count] {
+                tlb::flush(VirtAddr::new(addr));
+            }
+        }
+    }
+}
+
+struct Unmapper<'a, A> {
+    frame_allocator: &'a mut A,
+    free_frames: bool,
+    start: u64,
+    end: u64,
+    stats: UnmapStats,
+    batch: TlbBatch,
+}
+
+/// Unmaps every page in `start..start + size`. With `free_frames`, the
+/// frames behind the pages go back to `frame_allocator`; leave it off for
+/// memory the frame allocator doesn't own, like device memory. Page tables
+/// that become empty are freed either way, so the range must not reach
+/// into tables the bootloader set up. P3 tables outside the user region
+/// are the exception, address spaces share them.
+pub fn unmap_range<A>(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut A,
+    start: VirtAddr,
+    size: u64,
+    free_frames: bool,
+) -> Result<UnmapStats, UnmapRangeError>
+where
+    A: FrameDeallocator<Size4KiB> + FrameDeallocator<Size2MiB> + FrameDeallocator<Size1GiB>,
+{
+    let mut unmapper = Unmapper {
+        frame_allocator,
+        free_frames,
+        start: start.as_u64(),
+        end: start.as_u64().saturating_add(size),
+        stats: UnmapStats::default(),
+        batch: TlbBatch { pages: [0; MAX_SINGLE_FLUSHES], count: 0 },
+    };
+    if size == 0 {
+        return Ok(unmapper.stats);
+    }
+    // The P4 table itself is never freed
+    let (start, end) = (unmapper.start, unmapper.end);
+    let result = walk::walk(mapper.level_4_table(), start, end, &mut unmapper);
+    unmapper.batch.flush();
+    if unmapper.start < USER_REGION.start || unmapper.end > USER_REGION.end() {
+        // Only the active PCID was flushed
+        super::address_space::kernel_mappings_removed();
+    }
+    result.map(|_| unmapper.stats)
+}
+
+impl<'a, A> Unmapper<'a, A>
+where
+    A: FrameDeallocator<Size4KiB> + FrameDeallocator<Size2MiB> + FrameDeallocator<Size1GiB>,
+{
+    fn free_frame(&mut self, addr: u64, level: u8) {
+        let allocator = &mut *self.frame_allocator;
+        let address = x86_64::PhysAddr::new(addr);
+        match level {
+            3 => FrameDeallocator::<Size1GiB>::deallocate_frame(
+                allocator, PhysFrame::containing_address(address)),
+            2 => FrameDeallocator::<Size2MiB>::deallocate_frame(
+                allocator, PhysFrame::containing_address(address)),
+            _ => FrameDeallocator::<Size4KiB>::deallocate_frame(
+                allocator, PhysFrame::containing_address(address)),
+        }
+        self.stats.frames_freed += (entry_size(level) / entry_size(1)) as usize;
+    }
+}
+
+impl<'a, A> Visitor for Unmapper<'a, A>
+where
+    A: FrameDeallocator<Size4KiB> + FrameDeallocator<Size2MiB> + FrameDeallocator<Size1GiB>,
+{
+    type Error = UnmapRangeError;
+
+    fn leaf(&mut self, entry: &mut PageTableEntry, level: u8, start: u64)
+        -> Result<(), UnmapRangeError> {
+        if start < self.start || start.saturating_add(entry_size(level)) > self.end {
+            return Err(UnmapRangeError::PartialHugePage(VirtAddr::new(start)));
+        }
+        if self.free_frames {
+            self.free_frame(entry.addr().as_u64(), level);
+        }
+        entry.set_unused();
+        self.batch.add(start);
+        self.stats.pages += 1;
+        Ok(())
+    }
+
+    // Frees the table below `entry` if it is empty now
+    fn leave(&mut self, entry: &mut PageTableEntry, level: u8, start: u64)
+        -> Result<(), UnmapRangeError> {
+        // Outside the user region, every address space shares the P3
+        // tables of the kernel, so they have to stay
+        if level == 4 && !USER_REGION.contains(VirtAddr::new(start)) {
+            return Ok(());
+        }
+        let child_frame = PhysFrame::containing_address(entry.addr());
+        if unsafe { table_at(child_frame) }.iter().all(|entry| entry.is_unused()) {
+            entry.set_unused();
+            FrameDeallocator::<Size4KiB>::deallocate_frame(self.frame_allocator, child_frame);
+            self.stats.tables_freed += 1;
+        }
+        Ok(())
+    }
+}
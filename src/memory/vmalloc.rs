@@ -0,0 +1,350 @@
+// Kernel virtual address space manager
+//
+// Kernel virtual memory is split into fixed regions, one per user. Within
+// the vmalloc and ioremap regions, ranges are handed out and taken back by
+// a first fit allocator, so two subsystems can't end up on the same pages.
+// Lock order: address space, then MAPPER, then FRAME_ALLOCATOR. The heap
+// can't grow while MAPPER is held, so avoid allocating then.
+use alloc::collections::BTreeMap;
+use lazy_static::lazy_static;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags,
+    Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::unmap::{unmap_range, UnmapRangeError};
+use super::{BitmapFrameAllocator, FRAME_ALLOCATOR, MAPPER};
+use crate::allocator::{HEAP_START, HEAP_WINDOW_SIZE};
+use crate::spinlock::IrqSpinLock;
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+/// A range of kernel virtual memory set aside for one purpose
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: u64,
+    pub size: u64,
+}
+
+impl Region {
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        (self.start..self.end()).contains(&addr.as_u64())
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+}
+
+pub const HEAP_REGION: Region = Region {
+    name: "heap",
+    start: HEAP_START as u64,
+    size: HEAP_WINDOW_SIZE as u64,
+};
+
+pub const VMALLOC_REGION: Region = Region {
+    name: "vmalloc",
+    start: 0x_5000_0000_0000,
+    size: 0x100_0000_0000, // 1 TiB
+};
+
+pub const IOREMAP_REGION: Region = Region {
+    name: "ioremap",
+    start: 0x_5100_0000_0000,
+    size: 0x100_0000_0000, // 1 TiB
+};
+
+/// The part of an address space that belongs to it alone, see AddressSpace.
+/// Starts and ends on a P4 entry boundary.
+pub const USER_REGION: Region = Region {
+    name: "user",
+    start: 0x_6000_0000_0000,
+    size: 0x1000_0000_0000, // 16 TiB
+};
+
+/// Every region of virtual memory handed out by the kernel
+pub const REGIONS: &[Region] = &[HEAP_REGION, VMALLOC_REGION, IOREMAP_REGION, USER_REGION];
+
+#[derive(Debug)]
+pub enum VmError {
+    /// The region has no free range that is large enough
+    OutOfVirtualSpace,
+    /// The frame allocator ran out of frames
+    OutOfMemory,
+    /// MAPPER or FRAME_ALLOCATOR haven't been set up yet
+    NotInitialized,
+    /// The address wasn't returned by the matching allocation function
+    NotAllocated(VirtAddr),
+    /// The range lies outside the region it has to be in
+    OutsideRegion(VirtAddr),
+    Map(MapToError),
+    Unmap(UnmapRangeError),
+}
+
+impl From<MapToError> for VmError {
+    fn from(err: MapToError) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => VmError::OutOfMemory,
+            err => VmError::Map(err),
+        }
+    }
+}
+
+impl From<UnmapRangeError> for VmError {
+    fn from(err: UnmapRangeError) -> Self {
+        VmError::Unmap(err)
+    }
+}
+
+/// First fit allocator for the ranges of one region
+pub struct VirtualRangeAllocator {
+    region: Region,
+    // Start and size of every free range
+    free: BTreeMap<u64, u64>,
+    // Start and size of every handed out range
+    used: BTreeMap<u64, u64>,
+}
+
+impl VirtualRangeAllocator {
+    pub fn new(region: Region) -> Self {
+        let mut free = BTreeMap::new();
+        free.insert(region.start, region.size);
+        VirtualRangeAllocator {
+            region,
+            free,
+            used: BTreeMap::new(),
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Reserves `size` bytes (rounded up to pages) aligned to `align`
+    pub fn reserve(&mut self, size: u64, align: u64) -> Option<VirtAddr> {
+        let size = align_up(size.max(1), PAGE_SIZE);
+        let align = align.max(PAGE_SIZE);
+        let (range_start, range_size, start) = self.free
+            .iter()
+            .map(|(&range_start, &range_size)| {
+                (range_start, range_size, align_up(range_start, align))
+            })
+            .find(|&(range_start, range_size, start)| {
+                start + size <= range_start + range_size
+            })?;
+
+        // Split off whatever is left on either side
+        self.free.remove(&range_start);
+        if start > range_start {
+            self.free.insert(range_start, start - range_start);
+        }
+        let end = start + size;
+        if end < range_start + range_size {
+            self.free.insert(end, range_start + range_size - end);
+        }
+        self.used.insert(start, size);
+        Some(VirtAddr::new(start))
+    }
+
+    /// Returns a range from `reserve`, and its size
+    pub fn release(&mut self, addr: VirtAddr) -> Option<u64> {
+        let mut start = addr.as_u64();
+        let size = self.used.remove(&start)?;
+        let mut end = start + size;
+
+        // Merge with the free neighbours
+        if let Some(next_size) = self.free.remove(&end) {
+            end += next_size;
+        }
+        let previous = self.free.range(..start).next_back().map(|(&s, &n)| (s, n));
+        if let Some((previous_start, previous_size)) = previous {
+            if previous_start + previous_size == start {
+                self.free.remove(&previous_start);
+                start = previous_start;
+            }
+        }
+        self.free.insert(start, end - start);
+        Some(size)
+    }
+
+    /// Size of the range at `addr` if it is handed out
+    pub fn size_of(&self, addr: VirtAddr) -> Option<u64> {
+        self.used.get(&addr.as_u64()).cloned()
+    }
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+lazy_static! {
+    static ref VMALLOC_SPACE: IrqSpinLock<VirtualRangeAllocator> =
+        IrqSpinLock::new("vmalloc", VirtualRangeAllocator::new(VMALLOC_REGION));
+    static ref IOREMAP_SPACE: IrqSpinLock<VirtualRangeAllocator> =
+        IrqSpinLock::new("ioremap", VirtualRangeAllocator::new(IOREMAP_REGION));
+}
+
+/// Checks that no two regions overlap. Called once at boot.
+pub fn check_regions() {
+    for (i, a) in REGIONS.iter().enumerate() {
+        for b in &REGIONS[i + 1..] {
+            assert!(!a.overlaps(b), "regions {} and {} overlap", a.name, b.name);
+        }
+    }
+}
+
+fn pages(start: VirtAddr, size: u64) -> impl Iterator<Item = Page> {
+    let first = Page::containing_address(start);
+    let last = Page::containing_address(start + (size - 1));
+    Page::range_inclusive(first, last)
+}
+
+// Runs `f` with MAPPER and FRAME_ALLOCATOR locked. Range allocators are
+// only changed outside, since they allocate and the heap may need MAPPER
+// to grow.
+fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator)
+                  -> Result<R, VmError>) -> Result<R, VmError> {
+    let mut mapper = MAPPER.lock();
+    let mut frames = FRAME_ALLOCATOR.lock();
+    match (mapper.as_mut(), frames.as_mut()) {
+        (Some(mapper), Some(frames)) => f(mapper, frames),
+        _ => Err(VmError::NotInitialized),
+    }
+}
+
+/// Allocates `size` bytes of virtually contiguous memory backed by frames
+/// that need not be contiguous. An unmapped guard page follows it.
+pub fn vmalloc(size: usize, flags: PageTableFlags) -> Result<VirtAddr, VmError> {
+    let size = align_up((size as u64).max(1), PAGE_SIZE);
+    let mut space = VMALLOC_SPACE.lock();
+    let start = space
+        .reserve(size + PAGE_SIZE, PAGE_SIZE)
+        .ok_or(VmError::OutOfVirtualSpace)?;
+
+    let flags = flags | PageTableFlags::PRESENT;
+    let mut result = map_new_frames(start, size, flags);
+    if let Err(VmError::OutOfMemory) = result {
+        // Empty slabs may still hold frames
+        if super::slab::shrink_all() > 0 {
+            result = map_new_frames(start, size, flags);
+        }
+    }
+    match result {
+        Ok(()) => Ok(start),
+        Err(err) => {
+            space.release(start);
+            Err(err)
+        }
+    }
+}
+
+// Maps `start..start + size` to newly allocated frames, nothing if it fails
+fn map_new_frames(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), VmError> {
+    with_mapper(|mapper, frames| {
+        for (mapped, page) in pages(start, size).enumerate() {
+            let result = match FrameAllocator::<Size4KiB>::allocate_frame(frames) {
+                Some(frame) => unsafe {
+                    mapper.map_to(page, frame, flags, frames).map_err(|err| {
+                        frames.deallocate_frame(frame);
+                        err
+                    })
+                },
+                None => Err(MapToError::FrameAllocationFailed),
+            };
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    // Undo the pages mapped so far
+                    unmap_pages(mapper, frames, start, mapped as u64 * PAGE_SIZE, true);
+                    return Err(err.into());
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Frees memory from `vmalloc`
+pub fn vfree(addr: VirtAddr) -> Result<(), VmError> {
+    let mut space = VMALLOC_SPACE.lock();
+    // Without the guard page
+    let size = space.size_of(addr).ok_or(VmError::NotAllocated(addr))? - PAGE_SIZE;
+
+    with_mapper(|mapper, frames| {
+        unmap_pages(mapper, frames, addr, size, true);
+        Ok(())
+    })?;
+    space.release(addr);
+    Ok(())
+}
+
+/// Maps `size` bytes of device memory at `phys` uncached. Returns the
+/// address `phys` is mapped at.
+pub fn ioremap(phys: PhysAddr, size: usize) -> Result<VirtAddr, VmError> {
+    let flags = PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | super::no_execute();
+    ioremap_with_flags(phys, size, flags)
+}
+
+/// Like `ioremap`, but with the caller's choice of page flags
+pub fn ioremap_with_flags(phys: PhysAddr, size: usize, flags: PageTableFlags)
+    -> Result<VirtAddr, VmError> {
+    // The mapping has to start and end on page boundaries
+    let offset = phys.as_u64() % PAGE_SIZE;
+    let phys_start = phys.as_u64() - offset;
+    let size = align_up(offset + size as u64, PAGE_SIZE);
+
+    let mut space = IOREMAP_SPACE.lock();
+    let start = space.reserve(size, PAGE_SIZE).ok_or(VmError::OutOfVirtualSpace)?;
+
+    let result = with_mapper(|mapper, frames| {
+        let result = unsafe {
+            super::map_physical_range(mapper, frames, start, PhysAddr::new(phys_start),
+                                      size, flags | PageTableFlags::PRESENT)
+        };
+        if result.is_err() {
+            // Undo the pages mapped so far, the frames belong to the device
+            unmap_pages(mapper, frames, start, size, false);
+        }
+        result.map_err(VmError::from)
+    });
+    match result {
+        Ok(()) => Ok(start + offset),
+        Err(err) => {
+            space.release(start);
+            Err(err)
+        }
+    }
+}
+
+/// Removes a mapping from `ioremap`
+pub fn iounmap(addr: VirtAddr) -> Result<(), VmError> {
+    let start = VirtAddr::new(addr.as_u64() & !(PAGE_SIZE - 1));
+    let mut space = IOREMAP_SPACE.lock();
+    let size = space.size_of(start).ok_or(VmError::NotAllocated(addr))?;
+
+    with_mapper(|mapper, frames| {
+        unmap_pages(mapper, frames, start, size, false);
+        Ok(())
+    })?;
+    space.release(start);
+    Ok(())
+}
+
+// Unmaps the range, handing the frames back if they came from the frame
+// allocator. Every mapping in the region covers whole allocations, so no
+// huge page can be cut in half.
+fn unmap_pages(mapper: &mut OffsetPageTable<'static>, frames: &mut BitmapFrameAllocator,
+               start: VirtAddr, size: u64, free_frames: bool) {
+    unmap_range(mapper, frames, start, size, free_frames)
+        .expect("unmapping a partial huge page");
+}
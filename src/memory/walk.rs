@@ -0,0 +1,91 @@
+// Page table walking
+//
+// One walker for everything that reads or edits page tables directly:
+// translation, unmapping, the dumps and W^X. It visits the entries that
+// overlap a range of virtual memory in address order, and the visitor
+// decides what to do with them.
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+use super::physical_memory_offset;
+
+/// Size of the memory an entry at `level` maps
+pub fn entry_size(level: u8) -> u64 {
+    1 << (12 + 9 * (u64::from(level) - 1))
+}
+
+/// Sign extends a 48 bit address
+pub fn canonical(addr: u64) -> u64 {
+    ((addr << 16) as i64 >> 16) as u64
+}
+
+/// Whether a present entry maps memory instead of pointing at a table.
+/// Bit 7 means huge page at P3 and P2, but selects the PAT entry at P1.
+pub fn is_leaf(level: u8, flags: PageTableFlags) -> bool {
+    level == 1 || ((level == 3 || level == 2) && flags.contains(PageTableFlags::HUGE_PAGE))
+}
+
+/// The page table in `frame`, through the physical memory mapping.
+///
+/// Unsafe because the frame must hold a page table, and nothing else may
+/// be using it mutably.
+pub unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    let virt = frame.start_address().as_u64() + physical_memory_offset();
+    &mut *VirtAddr::new(virt).as_mut_ptr()
+}
+
+/// Callbacks for `walk`. `start` is the first address an entry maps.
+pub trait Visitor {
+    type Error;
+
+    /// A present entry that maps a page, 4 KiB or huge
+    fn leaf(&mut self, entry: &mut PageTableEntry, level: u8, start: u64)
+        -> Result<(), Self::Error>;
+
+    /// A present entry that points at a table. Returns whether to walk it.
+    fn enter(&mut self, _entry: &PageTableEntry, _level: u8, _start: u64) -> bool {
+        true
+    }
+
+    /// Called after the table below `entry` was walked
+    fn leave(&mut self, _entry: &mut PageTableEntry, _level: u8, _start: u64)
+        -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// An entry that isn't present
+    fn absent(&mut self, _entry: &PageTableEntry, _level: u8, _start: u64) {}
+}
+
+/// Walks the tables below the P4 table `level_4_table` for the entries
+/// that overlap `start..end`. Stops at the first error.
+pub fn walk<V: Visitor>(level_4_table: &mut PageTable, start: u64, end: u64, visitor: &mut V)
+    -> Result<(), V::Error> {
+    walk_table(level_4_table, 4, 0, start, end, visitor)
+}
+
+fn walk_table<V: Visitor>(table: &mut PageTable, level: u8, base: u64, start: u64, end: u64,
+                          visitor: &mut V) -> Result<(), V::Error> {
+    let size = entry_size(level);
+    for index in 0..512 {
+        let entry_start = canonical(base + index as u64 * size);
+        // The last entries reach the very top of the address space
+        let entry_end = entry_start.saturating_add(size);
+        if entry_end <= start || entry_start >= end {
+            continue;
+        }
+        let entry = &mut table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            visitor.absent(entry, level, entry_start);
+        } else if is_leaf(level, flags) {
+            visitor.leaf(entry, level, entry_start)?;
+        } else if visitor.enter(entry, level, entry_start) {
+            let child = unsafe { table_at(PhysFrame::containing_address(entry.addr())) };
+            walk_table(child, level - 1, entry_start, start, end, visitor)?;
+            visitor.leave(entry, level, entry_start)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,131 @@
+// Memory mapped device registers
+//
+// Device memory must not be cached like RAM. Mappings made here are
+// uncached, or write-combining for things like frame buffers, and are
+// accessed through volatile reads and writes only.
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::no_execute;
+use crate::memory::vmalloc::{ioremap_with_flags, iounmap, VmError};
+
+const IA32_PAT: u32 = 0x277;
+const PAT_WRITE_COMBINING: u64 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Every access goes to the device, in order. Right for registers.
+    Uncached,
+    /// Writes may be buffered and merged, reads are uncached. For frame
+    /// buffers and the like. Falls back to uncached without PAT support.
+    WriteCombining,
+}
+
+static PAT: Once<bool> = Once::new();
+
+// Programs PAT entry 1 (PWT set, PCD clear) as write-combining, the same
+// layout Linux uses. Returns false if the CPU has no PAT.
+fn init_pat() -> bool {
+    *PAT.call_once(|| {
+        use core::arch::x86_64::__cpuid;
+
+        if unsafe { __cpuid(1) }.edx & (1 << 16) == 0 {
+            return false;
+        }
+        let mut pat = Msr::new(IA32_PAT);
+        unsafe {
+            let value = pat.read();
+            pat.write(value & !(0xff << 8) | PAT_WRITE_COMBINING << 8);
+            // Nothing maps with PWT alone yet, but don't keep stale lines
+            asm!("wbinvd" ::: "memory" : "volatile");
+        }
+        crate::memory::address_space::flush_all();
+        true
+    })
+}
+
+fn flags(mode: CacheMode) -> PageTableFlags {
+    no_execute() | match mode {
+        CacheMode::WriteCombining if init_pat() => {
+            PageTableFlags::WRITABLE | PageTableFlags::WRITE_THROUGH
+        }
+        _ => PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+    }
+}
+
+/// Maps `len` bytes of device memory at `phys` uncached
+pub fn map<T>(phys: PhysAddr, len: usize) -> Result<Mmio<T>, VmError> {
+    map_with_mode(phys, len, CacheMode::Uncached)
+}
+
+/// Like `map`, with the caller's choice of caching
+pub fn map_with_mode<T>(phys: PhysAddr, len: usize, mode: CacheMode)
+    -> Result<Mmio<T>, VmError> {
+    assert!(len >= size_of::<T>(), "{} bytes can't hold the register block", len);
+    assert!(phys.as_u64() % align_of::<T>() as u64 == 0, "unaligned register block");
+    let base = ioremap_with_flags(phys, len, flags(mode))?;
+    Ok(Mmio { base, len, _marker: PhantomData })
+}
+
+/// A mapped block of device memory laid out as `T`. Unmapped on drop.
+#[derive(Debug)]
+pub struct Mmio<T> {
+    base: VirtAddr,
+    len: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+// The mapping is only reachable through this value
+unsafe impl<T: Send> Send for Mmio<T> {}
+
+impl<T> Mmio<T> {
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn as_ptr(&self) -> *mut T {
+        self.base.as_mut_ptr()
+    }
+
+    pub fn read(&self) -> T
+    where
+        T: Copy,
+    {
+        unsafe { self.as_ptr().read_volatile() }
+    }
+
+    pub fn write(&mut self, value: T) {
+        unsafe { self.as_ptr().write_volatile(value) }
+    }
+
+    /// Reads the `U` at byte `offset`
+    pub fn read_at<U: Copy>(&self, offset: usize) -> U {
+        unsafe { self.pointer_at::<U>(offset).read_volatile() }
+    }
+
+    /// Writes `value` at byte `offset`
+    pub fn write_at<U: Copy>(&mut self, offset: usize, value: U) {
+        unsafe { self.pointer_at::<U>(offset).write_volatile(value) }
+    }
+
+    fn pointer_at<U>(&self, offset: usize) -> *mut U {
+        assert!(offset + size_of::<U>() <= self.len, "offset {:#x} out of bounds", offset);
+        let addr = self.base + offset as u64;
+        assert!(addr.as_u64() % align_of::<U>() as u64 == 0, "unaligned offset {:#x}", offset);
+        addr.as_mut_ptr()
+    }
+}
+
+impl<T> Drop for Mmio<T> {
+    fn drop(&mut self) {
+        iounmap(self.base).expect("failed to unmap device memory");
+    }
+}
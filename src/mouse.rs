@@ -0,0 +1,224 @@
+// PS/2 mouse on the controller's second port
+//
+// The interrupt handler assembles packets and pushes decoded events into a
+// lock-free queue, consumed through MouseStream like keyboard input.
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::interrupts::{self, InterruptIndex};
+use crate::ps2::{self, Ps2Error, Ps2Port};
+use crate::spinlock::IrqSpinLock;
+use crate::task::irq_stream::{IrqQueue, IrqStream};
+use crate::task::StreamExt;
+use crate::vga_buffer;
+
+const DATA_PORT: u16 = 0x60;
+
+// Mouse commands
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const GET_DEVICE_ID: u8 = 0xf2;
+const SET_DEFAULTS: u8 = 0xf6;
+const ENABLE_REPORTING: u8 = 0xf4;
+
+// Device ID of a mouse with a scroll wheel (IntelliMouse)
+const INTELLIMOUSE_ID: u8 = 3;
+// Sample rates that unlock the scroll wheel on an IntelliMouse
+const INTELLIMOUSE_UNLOCK: [u8; 3] = [200, 100, 80];
+
+// Bits of the first packet byte
+const BUTTON_LEFT: u8 = 1 << 0;
+const BUTTON_RIGHT: u8 = 1 << 1;
+const BUTTON_MIDDLE: u8 = 1 << 2;
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+// Number of events buffered before mouse input is dropped
+const EVENT_QUEUE_SIZE: usize = 100;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// Relative motion and button state reported by one packet
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// Movement to the right
+    pub dx: i16,
+    /// Movement up
+    pub dy: i16,
+    /// Scroll wheel movement, negative is up
+    pub dz: i8,
+    pub buttons: MouseButtons,
+}
+
+// Collects the bytes of one packet
+struct PacketDecoder {
+    bytes: [u8; 4],
+    len: usize,
+    // 3 bytes, or 4 with a scroll wheel
+    packet_size: usize,
+}
+
+impl PacketDecoder {
+    const fn new() -> Self {
+        PacketDecoder {
+            bytes: [0; 4],
+            len: 0,
+            packet_size: 3,
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        // Resynchronize if a byte got lost: the first byte always has
+        // bit 3 set.
+        if self.len == 0 && byte & ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.packet_size {
+            return None;
+        }
+        self.len = 0;
+        Some(self.decode())
+    }
+
+    fn decode(&self) -> MouseEvent {
+        let flags = self.bytes[0];
+        // Motion is a 9 bit two's complement value with the sign bit in
+        // the flags byte. Overflowed values are useless, so drop them.
+        let axis = |value: u8, sign: u8, overflow: u8| -> i16 {
+            if flags & overflow != 0 {
+                0
+            } else if flags & sign != 0 {
+                i16::from(value) - 0x100
+            } else {
+                i16::from(value)
+            }
+        };
+        MouseEvent {
+            dx: axis(self.bytes[1], X_SIGN, X_OVERFLOW),
+            dy: axis(self.bytes[2], Y_SIGN, Y_OVERFLOW),
+            dz: if self.packet_size == 4 { self.bytes[3] as i8 } else { 0 },
+            buttons: MouseButtons {
+                left: flags & BUTTON_LEFT != 0,
+                right: flags & BUTTON_RIGHT != 0,
+                middle: flags & BUTTON_MIDDLE != 0,
+            },
+        }
+    }
+}
+
+static DECODER: IrqSpinLock<PacketDecoder> =
+    IrqSpinLock::new("mouse_decoder", PacketDecoder::new());
+
+// Opened by event_stream, which needs the heap. Events that arrive before
+// that are counted as dropped.
+static EVENTS: IrqQueue<MouseEvent> = IrqQueue::new("mouse event", EVENT_QUEUE_SIZE);
+
+/// Enables the second PS/2 port, detects a scroll wheel, and registers the
+/// mouse interrupt handler.
+pub fn init() -> Result<(), Ps2Error> {
+    let packet_size = {
+        let mut controller = ps2::CONTROLLER.lock();
+        controller.enable_port_2()?;
+        // The decoder would take the ACKs (0xfa) as the start of a packet
+        controller.with_irq_masked(Ps2Port::Second, |controller| {
+            controller.reset_device(Ps2Port::Second)?;
+            // After the self test result the mouse sends its device ID
+            controller.read_device_response()?;
+            controller.send_to_device(Ps2Port::Second, SET_DEFAULTS)?;
+
+            for &rate in INTELLIMOUSE_UNLOCK.iter() {
+                controller.send_to_device(Ps2Port::Second, SET_SAMPLE_RATE)?;
+                controller.send_to_device(Ps2Port::Second, rate)?;
+            }
+            controller.send_to_device(Ps2Port::Second, GET_DEVICE_ID)?;
+            let id = controller.read_device_response()?;
+
+            controller.send_to_device(Ps2Port::Second, ENABLE_REPORTING)?;
+            Ok(if id == INTELLIMOUSE_ID { 4 } else { 3 })
+        })?
+    };
+    DECODER.lock().packet_size = packet_size;
+
+    interrupts::register_irq(InterruptIndex::Mouse.irq(), mouse_interrupt_handler)
+        .expect("failed to register the mouse handler");
+    Ok(())
+}
+
+/// Number of mouse events lost so far
+pub fn dropped_events() -> u64 {
+    EVENTS.dropped()
+}
+
+fn mouse_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    let mut port = Port::new(DATA_PORT);
+    let byte: u8 = unsafe { port.read() };
+
+    let event = match DECODER.lock().add_byte(byte) {
+        Some(event) => event,
+        None => return,
+    };
+    EVENTS.push(event);
+}
+
+/// Stream of mouse events
+pub type MouseStream = IrqStream<MouseEvent>;
+
+/// Opens the mouse event stream. There can only be one reader, so this
+/// panics when called twice.
+pub fn event_stream() -> MouseStream {
+    EVENTS.open()
+}
+
+// Mouse movement per text cell
+const COUNTS_PER_COLUMN: i32 = 8;
+const COUNTS_PER_ROW: i32 = 16;
+
+/// Task that moves a text mode cursor with the mouse
+pub async fn track_cursor() {
+    let (width, height) = vga_buffer::size();
+    let max_x = (width as i32) * COUNTS_PER_COLUMN - 1;
+    let max_y = (height as i32) * COUNTS_PER_ROW - 1;
+    let (mut x, mut y) = (max_x / 2, max_y / 2);
+
+    let mut events = event_stream();
+    while let Some(event) = events.next().await {
+        x = (x + i32::from(event.dx)).max(0).min(max_x);
+        // Rows grow downwards, mouse movement upwards
+        y = (y - i32::from(event.dy)).max(0).min(max_y);
+        let row = (y / COUNTS_PER_ROW) as usize;
+        let column = (x / COUNTS_PER_COLUMN) as usize;
+        vga_buffer::set_mouse_cursor(Some((row, column)));
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_decode_mouse_packets() {
+    serial_print!("test_decode_mouse_packets... ");
+    let mut decoder = PacketDecoder::new();
+    // Out of sync byte is dropped, then left button, 5 right, 3 down
+    assert_eq!(decoder.add_byte(0x00), None);
+    assert_eq!(decoder.add_byte(ALWAYS_ONE | BUTTON_LEFT | Y_SIGN), None);
+    assert_eq!(decoder.add_byte(5), None);
+    let event = decoder.add_byte(0xfd).unwrap();
+    assert_eq!((event.dx, event.dy, event.dz), (5, -3, 0));
+    assert!(event.buttons.left && !event.buttons.right);
+
+    decoder.packet_size = 4;
+    decoder.add_byte(ALWAYS_ONE);
+    decoder.add_byte(0);
+    decoder.add_byte(0);
+    assert_eq!(decoder.add_byte(0xff).unwrap().dz, -1);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,316 @@
+// Reboot and power off
+//
+// Both try the ACPI way first and fall back to older mechanisms. The ACPI
+// tables are read through the physical memory mapping, so memory::init
+// must have run before.
+use core::ptr;
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+use crate::{memory, println};
+
+// PS/2 controller ports and the command that pulses the CPU reset line
+const PS2_STATUS: u16 = 0x64;
+const PS2_COMMAND: u16 = 0x64;
+const PS2_INPUT_FULL: u8 = 1 << 1;
+const PS2_PULSE_RESET: u8 = 0xfe;
+
+// Shutdown ports of QEMU (with the PIIX4 power management) and Bochs
+const QEMU_SHUTDOWN_PORT: u16 = 0x604;
+const BOCHS_SHUTDOWN_PORT: u16 = 0xb004;
+const EMULATOR_SHUTDOWN: u16 = 0x2000;
+
+// PM1 control register bits
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_EN: u16 = 1 << 13;
+
+// FADT flag saying the reset register is valid
+const RESET_REG_SUP: u32 = 1 << 10;
+
+// Address spaces of an ACPI generic address
+const SYSTEM_MEMORY: u8 = 0;
+const SYSTEM_IO: u8 = 1;
+
+const SDT_HEADER_SIZE: u64 = 36;
+
+/// Power management registers found in the ACPI tables
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiPower {
+    // Address space, address and value of the reset register
+    reset: Option<(u8, u64, u8)>,
+    smi_command: u16,
+    acpi_enable: u8,
+    pm1a_control: u16,
+    pm1b_control: u16,
+    // SLP_TYPa and SLP_TYPb for the S5 (soft off) state
+    s5: Option<(u16, u16)>,
+}
+
+static ACPI: Once<Option<AcpiPower>> = Once::new();
+
+/// Returns the ACPI power registers, looking them up the first time
+pub fn acpi() -> Option<AcpiPower> {
+    *ACPI.call_once(find_acpi_power)
+}
+
+/// Restarts the machine. Tries the ACPI reset register, then the PS/2
+/// controller, then a triple fault, which always works.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    if let Some((space, address, value)) = acpi().and_then(|acpi| acpi.reset) {
+        match space {
+            SYSTEM_IO => unsafe { Port::<u8>::new(address as u16).write(value) },
+            SYSTEM_MEMORY => unsafe { write_physical(address, value) },
+            _ => {}
+        }
+        wait();
+    }
+
+    unsafe {
+        let mut status = Port::<u8>::new(PS2_STATUS);
+        for _ in 0..100_000 {
+            if status.read() & PS2_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        Port::<u8>::new(PS2_COMMAND).write(PS2_PULSE_RESET);
+    }
+    wait();
+
+    triple_fault()
+}
+
+/// Turns the machine off. Tries ACPI S5, then the shutdown ports of QEMU
+/// and Bochs, and halts if nothing worked.
+pub fn power_off() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    if let Some(acpi) = acpi() {
+        if let Some((slp_typ_a, slp_typ_b)) = acpi.s5 {
+            unsafe { acpi.enter_sleep_state(slp_typ_a, slp_typ_b) };
+            wait();
+        }
+    }
+
+    unsafe {
+        Port::<u16>::new(QEMU_SHUTDOWN_PORT).write(EMULATOR_SHUTDOWN);
+        Port::<u16>::new(BOCHS_SHUTDOWN_PORT).write(EMULATOR_SHUTDOWN);
+    }
+    wait();
+
+    println!("Power off failed; it is now safe to turn off the machine");
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+impl AcpiPower {
+    unsafe fn enter_sleep_state(&self, slp_typ_a: u16, slp_typ_b: u16) {
+        let mut pm1a = Port::<u16>::new(self.pm1a_control);
+
+        // Firmware may still own the power management hardware
+        if pm1a.read() & SCI_EN == 0 && self.smi_command != 0 {
+            Port::<u8>::new(self.smi_command).write(self.acpi_enable);
+            for _ in 0..1_000_000 {
+                if pm1a.read() & SCI_EN != 0 {
+                    break;
+                }
+            }
+        }
+
+        let value = pm1a.read();
+        pm1a.write(value | (slp_typ_a << SLP_TYP_SHIFT) | SLP_EN);
+        if self.pm1b_control != 0 {
+            let mut pm1b = Port::<u16>::new(self.pm1b_control);
+            let value = pm1b.read();
+            pm1b.write(value | (slp_typ_b << SLP_TYP_SHIFT) | SLP_EN);
+        }
+    }
+}
+
+// Gives a reset or power off request some time to take effect
+fn wait() {
+    for _ in 0..10_000_000 {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+// Loads an empty IDT, so the next exception can't be delivered
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+
+    let empty = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe { lidt(&empty) };
+    x86_64::instructions::interrupts::int3();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+unsafe fn read_physical<T: Copy>(address: u64) -> T {
+    let virt = address + memory::physical_memory_offset();
+    ptr::read_unaligned(virt as *const T)
+}
+
+unsafe fn write_physical<T: Copy>(address: u64, value: T) {
+    let virt = address + memory::physical_memory_offset();
+    ptr::write_volatile(virt as *mut T, value);
+}
+
+fn checksum_ok(address: u64, length: u64) -> bool {
+    let sum = (0..length).fold(0u8, |sum, i| {
+        sum.wrapping_add(unsafe { read_physical::<u8>(address + i) })
+    });
+    sum == 0
+}
+
+// Searches the EBDA and the BIOS area for the root system description
+// pointer
+fn find_rsdp() -> Option<u64> {
+    let ebda = u64::from(unsafe { read_physical::<u16>(0x40e) }) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe0000, 0x100000)];
+    for &(start, end) in areas.iter() {
+        for address in (start..end).step_by(16) {
+            let signature: [u8; 8] = unsafe { read_physical(address) };
+            if &signature == b"RSD PTR " && checksum_ok(address, 20) {
+                return Some(address);
+            }
+        }
+    }
+    None
+}
+
+// Finds the table with `signature` in the RSDT or XSDT
+fn find_table(rsdp: u64, signature: &[u8; 4]) -> Option<u64> {
+    let revision: u8 = unsafe { read_physical(rsdp + 15) };
+    let (root, entry_size) = if revision >= 2 {
+        (unsafe { read_physical::<u64>(rsdp + 24) }, 8)
+    } else {
+        (u64::from(unsafe { read_physical::<u32>(rsdp + 16) }), 4)
+    };
+    let length = u64::from(unsafe { read_physical::<u32>(root + 4) });
+
+    for entry in (root + SDT_HEADER_SIZE..root + length).step_by(entry_size) {
+        let table = if entry_size == 8 {
+            unsafe { read_physical::<u64>(entry) }
+        } else {
+            u64::from(unsafe { read_physical::<u32>(entry) })
+        };
+        let table_signature: [u8; 4] = unsafe { read_physical(table) };
+        let table_length = u64::from(unsafe { read_physical::<u32>(table + 4) });
+        if &table_signature == signature && checksum_ok(table, table_length) {
+            return Some(table);
+        }
+    }
+    None
+}
+
+fn find_acpi_power() -> Option<AcpiPower> {
+    let rsdp = find_rsdp()?;
+    let fadt = find_table(rsdp, b"FACP")?;
+    let fadt_length = unsafe { read_physical::<u32>(fadt + 4) };
+    let read_u8 = |offset| unsafe { read_physical::<u8>(fadt + offset) };
+    let read_u32 = |offset| unsafe { read_physical::<u32>(fadt + offset) };
+
+    // The reset register came with FADT revision 2
+    let flags = read_u32(112);
+    let reset = if fadt_length >= 129 && flags & RESET_REG_SUP != 0 {
+        let address = unsafe { read_physical::<u64>(fadt + 120) };
+        Some((read_u8(116), address, read_u8(128)))
+    } else {
+        None
+    };
+
+    let dsdt = if fadt_length >= 148 && unsafe { read_physical::<u64>(fadt + 140) } != 0 {
+        unsafe { read_physical::<u64>(fadt + 140) }
+    } else {
+        u64::from(read_u32(40))
+    };
+    let dsdt_length = if dsdt != 0 { unsafe { read_physical::<u32>(dsdt + 4) } } else { 0 };
+    // A DSDT shorter than its header is broken, treat it as having no \_S5
+    let s5 = if u64::from(dsdt_length) > SDT_HEADER_SIZE {
+        let virt = dsdt + memory::physical_memory_offset();
+        let aml = unsafe { core::slice::from_raw_parts(virt as *const u8, dsdt_length as usize) };
+        parse_s5(&aml[SDT_HEADER_SIZE as usize..])
+    } else {
+        None
+    };
+
+    Some(AcpiPower {
+        reset,
+        smi_command: read_u32(48) as u16,
+        acpi_enable: read_u8(52),
+        pm1a_control: read_u32(64) as u16,
+        pm1b_control: read_u32(68) as u16,
+        s5,
+    })
+}
+
+/// Finds the `_S5_` package in AML and returns SLP_TYPa and SLP_TYPb.
+///
+/// Only handles the usual encoding `Name(_S5, Package() { a, b, ... })`
+/// instead of interpreting the AML.
+fn parse_s5(aml: &[u8]) -> Option<(u16, u16)> {
+    let start = aml.windows(4).position(|window| window == b"_S5_")?;
+    // NameOp, optionally followed by the root prefix
+    let name_op = match start {
+        0 => return None,
+        1 => aml[0],
+        _ if aml[start - 1] == b'\\' => aml[start - 2],
+        _ => aml[start - 1],
+    };
+    if name_op != 0x08 {
+        return None;
+    }
+
+    let mut rest = aml.get(start + 4..)?;
+    // PackageOp
+    if *rest.first()? != 0x12 {
+        return None;
+    }
+    // The top two bits of the package length tell how many bytes follow
+    let length_bytes = usize::from(*rest.get(1)? >> 6);
+    // Skip PackageOp, the package length and the element count
+    rest = rest.get(2 + length_bytes + 1..)?;
+
+    let mut element = || -> Option<u16> {
+        let value = match *rest.first()? {
+            // BytePrefix
+            0x0a => {
+                let value = *rest.get(1)?;
+                rest = &rest[2..];
+                return Some(u16::from(value));
+            }
+            // ZeroOp, OneOp, OnesOp
+            0x00 => 0,
+            0x01 => 1,
+            0xff => 0xff,
+            _ => return None,
+        };
+        rest = &rest[1..];
+        Some(value)
+    };
+    let slp_typ_a = element()?;
+    let slp_typ_b = element()?;
+    Some((slp_typ_a & 0x7, slp_typ_b & 0x7))
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_parse_s5() {
+    serial_print!("test_parse_s5... ");
+    // Name (_S5, Package (0x04) { 0x05, Zero, Zero, Zero }) from QEMU
+    let aml = [0x10, 0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04,
+               0x0a, 0x05, 0x00, 0x00, 0x00];
+    assert_eq!(parse_s5(&aml), Some((5, 0)));
+    // Same with the root prefix and byte encoded values
+    let aml = [0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x02,
+               0x0a, 0x07, 0x0a, 0x07];
+    assert_eq!(parse_s5(&aml), Some((7, 7)));
+    assert_eq!(parse_s5(b"_S4_"), None);
+    serial_println!("[ok]");
+}
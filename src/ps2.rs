@@ -0,0 +1,389 @@
+// 8042 PS/2 controller and keyboard commands
+//
+// Everything here polls the controller's status register. The controller
+// lock disables interrupts, so the keyboard interrupt handler can't steal
+// the device's responses while a command is in flight.
+use lazy_static::lazy_static;
+use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
+
+use crate::spinlock::IrqSpinLock;
+
+const DATA_PORT: u16 = 0x60;
+// Reading gives the status register, writing sends a controller command
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+// Status register bits
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+// Controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_PORT_2: u8 = 0xa7;
+const ENABLE_PORT_2: u8 = 0xa8;
+const TEST_PORT_2: u8 = 0xa9;
+const SELF_TEST: u8 = 0xaa;
+const TEST_PORT_1: u8 = 0xab;
+const DISABLE_PORT_1: u8 = 0xad;
+const ENABLE_PORT_1: u8 = 0xae;
+const WRITE_PORT_2: u8 = 0xd4;
+
+// Controller responses
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// Configuration byte bits
+const CONFIG_PORT_1_IRQ: u8 = 1 << 0;
+const CONFIG_PORT_2_IRQ: u8 = 1 << 1;
+const CONFIG_PORT_1_CLOCK_DISABLED: u8 = 1 << 4;
+const CONFIG_PORT_2_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+// Device commands and responses
+const DEVICE_RESET: u8 = 0xff;
+const DEVICE_ACK: u8 = 0xfa;
+const DEVICE_RESEND: u8 = 0xfe;
+const DEVICE_SELF_TEST_PASSED: u8 = 0xaa;
+const KEYBOARD_SET_LEDS: u8 = 0xed;
+const KEYBOARD_SCANCODE_SET: u8 = 0xf0;
+const KEYBOARD_SET_TYPEMATIC: u8 = 0xf3;
+
+// How often a device command is sent again when the device asks for it
+const MAX_RESENDS: usize = 3;
+// Status register reads before giving up on the controller
+const TIMEOUT_POLLS: usize = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Port {
+    First,
+    Second,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    Timeout,
+    SelfTestFailed(u8),
+    PortTestFailed(Ps2Port, u8),
+    NoSuchPort(Ps2Port),
+    TooManyResends,
+    UnexpectedResponse(u8),
+    InvalidScancodeSet(u8),
+}
+
+/// What `init` found out about the controller
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerInfo {
+    pub port_1: bool,
+    pub port_2: bool,
+}
+
+pub struct Controller {
+    data: Port<u8>,
+    status: PortReadOnly<u8>,
+    command: PortWriteOnly<u8>,
+    info: ControllerInfo,
+}
+
+impl Controller {
+    fn new() -> Self {
+        Controller {
+            data: Port::new(DATA_PORT),
+            status: PortReadOnly::new(STATUS_PORT),
+            command: PortWriteOnly::new(COMMAND_PORT),
+            info: ControllerInfo::default(),
+        }
+    }
+
+    fn status(&mut self) -> u8 {
+        unsafe { self.status.read() }
+    }
+
+    fn wait_for(&mut self, ready: impl Fn(u8) -> bool) -> Result<(), Ps2Error> {
+        for _ in 0..TIMEOUT_POLLS {
+            if ready(self.status()) {
+                return Ok(());
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    fn send_command(&mut self, command: u8) -> Result<(), Ps2Error> {
+        self.wait_for(|status| status & STATUS_INPUT_FULL == 0)?;
+        unsafe { self.command.write(command) };
+        Ok(())
+    }
+
+    fn read_data(&mut self) -> Result<u8, Ps2Error> {
+        self.wait_for(|status| status & STATUS_OUTPUT_FULL != 0)?;
+        Ok(unsafe { self.data.read() })
+    }
+
+    fn write_data(&mut self, byte: u8) -> Result<(), Ps2Error> {
+        self.wait_for(|status| status & STATUS_INPUT_FULL == 0)?;
+        unsafe { self.data.write(byte) };
+        Ok(())
+    }
+
+    // Drops whatever the devices sent before we started talking
+    fn flush_output(&mut self) {
+        while self.status() & STATUS_OUTPUT_FULL != 0 {
+            unsafe { self.data.read() };
+        }
+    }
+
+    fn read_config(&mut self) -> Result<u8, Ps2Error> {
+        self.send_command(READ_CONFIG)?;
+        self.read_data()
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<(), Ps2Error> {
+        self.send_command(WRITE_CONFIG)?;
+        self.write_data(config)
+    }
+
+    fn update_config(&mut self, f: impl FnOnce(u8) -> u8)
+        -> Result<(), Ps2Error> {
+        let config = self.read_config()?;
+        self.write_config(f(config))
+    }
+
+    fn initialize(&mut self) -> Result<ControllerInfo, Ps2Error> {
+        // Keep the devices quiet while we set the controller up
+        self.send_command(DISABLE_PORT_1)?;
+        self.send_command(DISABLE_PORT_2)?;
+        self.flush_output();
+
+        let config = self.read_config()?
+            & !(CONFIG_PORT_1_IRQ | CONFIG_PORT_2_IRQ);
+        self.write_config(config)?;
+
+        self.send_command(SELF_TEST)?;
+        match self.read_data()? {
+            SELF_TEST_PASSED => {}
+            response => return Err(Ps2Error::SelfTestFailed(response)),
+        }
+        // The self test resets the controller on some hardware
+        self.write_config(config)?;
+
+        // The second port exists if enabling it clears its clock bit
+        let mut info = ControllerInfo::default();
+        if config & CONFIG_PORT_2_CLOCK_DISABLED != 0 {
+            self.send_command(ENABLE_PORT_2)?;
+            info.port_2 =
+                self.read_config()? & CONFIG_PORT_2_CLOCK_DISABLED == 0;
+            self.send_command(DISABLE_PORT_2)?;
+        }
+
+        self.send_command(TEST_PORT_1)?;
+        match self.read_data()? {
+            PORT_TEST_PASSED => info.port_1 = true,
+            response => {
+                return Err(Ps2Error::PortTestFailed(Ps2Port::First, response))
+            }
+        }
+        if info.port_2 {
+            self.send_command(TEST_PORT_2)?;
+            info.port_2 = self.read_data()? == PORT_TEST_PASSED;
+        }
+
+        // The keyboard decoder expects scancode set 1, so keep translation
+        // on. The second port stays disabled until a driver enables it.
+        self.send_command(ENABLE_PORT_1)?;
+        self.update_config(|config| {
+            (config | CONFIG_TRANSLATION) & !CONFIG_PORT_1_CLOCK_DISABLED
+        })?;
+
+        // Reset before the interrupt is on, the handler would see the ACK
+        // and the self test result as scancodes
+        self.info = info;
+        self.reset_device(Ps2Port::First)?;
+        self.update_config(|config| config | CONFIG_PORT_1_IRQ)?;
+        Ok(info)
+    }
+
+    /// Sends a byte to a device and waits for its ACK, sending the byte
+    /// again when the device asks for it.
+    pub fn send_to_device(&mut self, port: Ps2Port, byte: u8)
+        -> Result<(), Ps2Error> {
+        let present = match port {
+            Ps2Port::First => self.info.port_1,
+            Ps2Port::Second => self.info.port_2,
+        };
+        if !present {
+            return Err(Ps2Error::NoSuchPort(port));
+        }
+
+        for _ in 0..MAX_RESENDS {
+            if port == Ps2Port::Second {
+                self.send_command(WRITE_PORT_2)?;
+            }
+            self.write_data(byte)?;
+            match self.read_data()? {
+                DEVICE_ACK => return Ok(()),
+                DEVICE_RESEND => continue,
+                response => return Err(Ps2Error::UnexpectedResponse(response)),
+            }
+        }
+        Err(Ps2Error::TooManyResends)
+    }
+
+    /// Runs `f` with the port's interrupt turned off in the controller.
+    /// Commands go through this, so their ACKs and responses don't also
+    /// reach the device's interrupt handler.
+    pub fn with_irq_masked<T>(&mut self, port: Ps2Port,
+                              f: impl FnOnce(&mut Self) -> Result<T, Ps2Error>)
+        -> Result<T, Ps2Error> {
+        let irq = match port {
+            Ps2Port::First => CONFIG_PORT_1_IRQ,
+            Ps2Port::Second => CONFIG_PORT_2_IRQ,
+        };
+        let config = self.read_config()?;
+        if config & irq == 0 {
+            return f(self);
+        }
+        self.write_config(config & !irq)?;
+        let result = f(self);
+        let restored = self.write_config(config);
+        let value = result?;
+        restored?;
+        Ok(value)
+    }
+
+    /// Reads a response byte sent by a device after its ACK
+    pub fn read_device_response(&mut self) -> Result<u8, Ps2Error> {
+        self.read_data()
+    }
+
+    pub fn reset_device(&mut self, port: Ps2Port) -> Result<(), Ps2Error> {
+        self.send_to_device(port, DEVICE_RESET)?;
+        match self.read_data()? {
+            DEVICE_SELF_TEST_PASSED => Ok(()),
+            response => Err(Ps2Error::UnexpectedResponse(response)),
+        }
+    }
+
+    /// Enables the second port and its interrupt
+    pub fn enable_port_2(&mut self) -> Result<(), Ps2Error> {
+        if !self.info.port_2 {
+            return Err(Ps2Error::NoSuchPort(Ps2Port::Second));
+        }
+        self.send_command(ENABLE_PORT_2)?;
+        self.update_config(|config| {
+            (config | CONFIG_PORT_2_IRQ) & !CONFIG_PORT_2_CLOCK_DISABLED
+        })
+    }
+
+    pub fn info(&self) -> ControllerInfo {
+        self.info
+    }
+}
+
+lazy_static! {
+    pub static ref CONTROLLER: IrqSpinLock<Controller> =
+        IrqSpinLock::new("ps2", Controller::new());
+}
+
+/// Initializes the controller and resets the keyboard. Must run before
+/// the keyboard interrupt is unmasked.
+pub fn init() -> Result<ControllerInfo, Ps2Error> {
+    CONTROLLER.lock().initialize()
+}
+
+/// State of the keyboard LEDs
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Leds {
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+
+impl Leds {
+    fn as_u8(self) -> u8 {
+        (self.scroll_lock as u8)
+            | (self.num_lock as u8) << 1
+            | (self.caps_lock as u8) << 2
+    }
+}
+
+pub fn set_leds(leds: Leds) -> Result<(), Ps2Error> {
+    CONTROLLER.lock().with_irq_masked(Ps2Port::First, |controller| {
+        controller.send_to_device(Ps2Port::First, KEYBOARD_SET_LEDS)?;
+        controller.send_to_device(Ps2Port::First, leds.as_u8())
+    })
+}
+
+/// Delay before a held key starts repeating
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TypematicDelay {
+    Ms250 = 0,
+    Ms500 = 1,
+    Ms750 = 2,
+    Ms1000 = 3,
+}
+
+/// Sets the repeat delay and rate of held keys. The rate goes from 0
+/// (30 repeats per second) to 31 (2 repeats per second).
+pub fn set_typematic(delay: TypematicDelay, rate: u8)
+    -> Result<(), Ps2Error> {
+    let value = (delay as u8) << 5 | rate.min(31);
+    CONTROLLER.lock().with_irq_masked(Ps2Port::First, |controller| {
+        controller.send_to_device(Ps2Port::First, KEYBOARD_SET_TYPEMATIC)?;
+        controller.send_to_device(Ps2Port::First, value)
+    })
+}
+
+/// Switches the keyboard to scancode set 1, 2 or 3.
+///
+/// The keyboard decoder expects set 1 after the controller's translation.
+/// With translation on, keep the keyboard on set 2.
+pub fn set_scancode_set(set: u8) -> Result<(), Ps2Error> {
+    // 0 would ask for the current set instead, see `scancode_set`
+    if !(1..=3).contains(&set) {
+        return Err(Ps2Error::InvalidScancodeSet(set));
+    }
+    CONTROLLER.lock().with_irq_masked(Ps2Port::First, |controller| {
+        controller.send_to_device(Ps2Port::First, KEYBOARD_SCANCODE_SET)?;
+        controller.send_to_device(Ps2Port::First, set)
+    })
+}
+
+/// Returns the keyboard's current scancode set
+pub fn scancode_set() -> Result<u8, Ps2Error> {
+    let response = CONTROLLER.lock().with_irq_masked(Ps2Port::First, |controller| {
+        controller.send_to_device(Ps2Port::First, KEYBOARD_SCANCODE_SET)?;
+        controller.send_to_device(Ps2Port::First, 0)?;
+        controller.read_device_response()
+    })?;
+    // Translation also applies to the answer
+    match response {
+        1 | 0x43 => Ok(1),
+        2 | 0x41 => Ok(2),
+        3 | 0x3f => Ok(3),
+        response => Err(Ps2Error::UnexpectedResponse(response)),
+    }
+}
+
+/// Turns the controller's translation of scancodes to set 1 on or off
+pub fn set_translation(enabled: bool) -> Result<(), Ps2Error> {
+    CONTROLLER.lock().update_config(|config| {
+        if enabled {
+            config | CONFIG_TRANSLATION
+        } else {
+            config & !CONFIG_TRANSLATION
+        }
+    })
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_invalid_scancode_set() {
+    serial_print!("test_invalid_scancode_set... ");
+    // Rejected before anything is sent to the keyboard
+    assert_eq!(set_scancode_set(0), Err(Ps2Error::InvalidScancodeSet(0)));
+    assert_eq!(set_scancode_set(4), Err(Ps2Error::InvalidScancodeSet(4)));
+    serial_println!("[ok]");
+}
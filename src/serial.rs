@@ -1,26 +1,83 @@
 use uart_16550::SerialPort;
-use spin::Mutex;
 use lazy_static::lazy_static;
+use crate::spinlock::IrqSpinLock;
+use x86_64::instructions::port::{Port, PortReadOnly};
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::interrupts::{self, InterruptIndex};
+use crate::task::irq_stream::{IrqQueue, IrqStream};
+
+// 0x3F8 is the standard port number of the first serial interface.
+const COM1: u16 = 0x3F8;
 
 lazy_static! {
     // Use port I/O, just like isa-debug-exit.
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        // 0x3F8 is the standard port number of the first serial interface.
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    pub static ref SERIAL1: IrqSpinLock<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSpinLock::new("serial1", serial_port)
     };
 }
 
+// Line status register and its "data ready" bit
+const LINE_STATUS: u16 = COM1 + 5;
+const DATA_READY: u8 = 1;
+
+// Number of received bytes buffered before input is dropped
+const INPUT_QUEUE_SIZE: usize = 100;
+
+// Opened by input_stream, which needs the heap. Input that arrives before
+// that has no reader and is counted as dropped.
+static INPUT: IrqQueue<u8> = IrqQueue::new("serial input", INPUT_QUEUE_SIZE);
+
+/// Number of received bytes lost so far
+pub fn dropped_input() -> u64 {
+    INPUT.dropped()
+}
+
+/// Initializes COM1, which enables its receive interrupt, and registers
+/// the interrupt handler
+pub fn init() {
+    lazy_static::initialize(&SERIAL1);
+    interrupts::register_irq(InterruptIndex::Com1.irq(), com1_interrupt_handler)
+        .expect("failed to register the serial handler");
+}
+
+fn com1_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    let mut status = PortReadOnly::<u8>::new(LINE_STATUS);
+    let mut data = Port::<u8>::new(COM1);
+    // The UART has a FIFO, so there may be more than one byte
+    while unsafe { status.read() } & DATA_READY != 0 {
+        INPUT.push(unsafe { data.read() });
+    }
+}
+
+/// Stream of the bytes received on COM1
+pub type SerialStream = IrqStream<u8>;
+
+/// Opens the COM1 input stream. There can only be one reader, so this
+/// panics when called twice.
+pub fn input_stream() -> SerialStream {
+    INPUT.open()
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    // Avoid dead locks
-    interrupts::without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
-    });
+    SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+}
+
+/// Prints to COM1 without taking the SERIAL1 lock.
+///
+/// Only for reporting dead locks and hangs, when the lock may be held by
+/// the code we are reporting on. Output may interleave with other output.
+#[doc(hidden)]
+pub fn _emergency_print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    // The port was initialized by SERIAL1 already
+    let mut serial_port = unsafe { SerialPort::new(COM1) };
+    let _ = serial_port.write_fmt(args);
 }
 
 /// Prints to the host through the serial interface
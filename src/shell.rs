@@ -0,0 +1,418 @@
+// Built-in kernel shell
+//
+// Runs as a task on a terminal and offers a few commands to inspect the
+// running kernel. Commands write to the terminal they were typed on.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::interrupts;
+use crate::keyboard;
+use crate::memory;
+use crate::mouse;
+use crate::power;
+use crate::serial;
+use crate::task::executor;
+use crate::task::StreamExt;
+use crate::tty::{Tty, TtyConfig, TtyEvent, TtyOutput};
+use crate::{allocator, vga_buffer};
+
+const PROMPT: &str = "> ";
+
+// Number of lines kept in the history
+const HISTORY_SIZE: usize = 32;
+
+type CommandResult = Result<(), String>;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    run: fn(&mut TtyOutput, &[&str]) -> CommandResult,
+}
+
+const COMMANDS: &[Command] = &[
+    Command { name: "help", usage: "", help: "list commands", run: help },
+    Command { name: "meminfo", usage: "", help: "frame and heap usage", run: meminfo },
+    Command { name: "heapstat", usage: "", help: "heap allocations by size", run: heapstat },
+    Command { name: "slabinfo", usage: "", help: "object cache usage", run: slabinfo },
+    Command { name: "irqstat", usage: "", help: "interrupt statistics", run: irqstat },
+    Command { name: "uptime", usage: "", help: "time since boot", run: uptime },
+    Command {
+        name: "pagewalk",
+        usage: "<addr>",
+        help: "show the page table entries for an address",
+        run: pagewalk,
+    },
+    Command {
+        name: "ptdump",
+        usage: "[check]",
+        help: "list all mappings, or only W+X and misplaced user ones",
+        run: ptdump,
+    },
+    Command {
+        name: "peek",
+        usage: "<addr> [count]",
+        help: "read up to 512 64 bit words",
+        run: peek,
+    },
+    Command {
+        name: "poke",
+        usage: "<addr> <value>",
+        help: "write a 64 bit word",
+        run: poke,
+    },
+    Command { name: "reboot", usage: "", help: "restart the machine", run: reboot },
+    Command { name: "shutdown", usage: "", help: "power off", run: shutdown },
+    Command { name: "clear", usage: "", help: "clear the screen", run: clear },
+];
+
+// Commands whose name starts with `prefix`
+fn matching_commands(prefix: &str) -> impl Iterator<Item = &'static Command> + '_ {
+    COMMANDS.iter().filter(move |command| command.name.starts_with(prefix))
+}
+
+/// Parses a decimal number or a hex number with a 0x prefix
+fn parse_number(s: &str) -> Option<u64> {
+    if s.starts_with("0x") {
+        u64::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn parse_arg(args: &[&str], index: usize) -> Result<u64, String> {
+    let arg = args.get(index).ok_or_else(|| String::from("missing argument"))?;
+    parse_number(arg).ok_or_else(|| alloc::format!("not a number: {}", arg))
+}
+
+struct Shell {
+    tty: Tty,
+    history: Vec<String>,
+    // Position while browsing the history, history.len() when not
+    history_position: usize,
+}
+
+impl Shell {
+    fn prompt(&mut self) {
+        let _ = self.tty.output().write_str(PROMPT);
+    }
+
+    fn handle(&mut self, event: TtyEvent) {
+        match event {
+            TtyEvent::Line(line) => {
+                self.execute(&line);
+                if !line.trim().is_empty() {
+                    if self.history.len() == HISTORY_SIZE {
+                        self.history.remove(0);
+                    }
+                    self.history.push(line);
+                }
+                self.history_position = self.history.len();
+                self.prompt();
+            }
+            TtyEvent::HistoryUp => {
+                if self.history_position > 0 {
+                    self.history_position -= 1;
+                    let line = self.history[self.history_position].clone();
+                    self.tty.set_line(&line);
+                }
+            }
+            TtyEvent::HistoryDown => {
+                if self.history_position < self.history.len() {
+                    self.history_position += 1;
+                    let line = self.history
+                        .get(self.history_position)
+                        .cloned()
+                        .unwrap_or_default();
+                    self.tty.set_line(&line);
+                }
+            }
+            TtyEvent::Complete => self.complete(),
+            // Nothing to interrupt or suspend, the line is gone though
+            TtyEvent::Interrupt | TtyEvent::Suspend | TtyEvent::EndOfFile => {
+                self.history_position = self.history.len();
+                self.prompt();
+            }
+            TtyEvent::Char(_) => {}
+        }
+    }
+
+    fn execute(&mut self, line: &str) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return,
+        };
+        let mut output = self.tty.output();
+        match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) => {
+                if let Err(err) = (command.run)(&mut output, args) {
+                    let _ = writeln!(output, "{}: {}", name, err);
+                    if !command.usage.is_empty() {
+                        let _ = writeln!(output, "usage: {} {}", name, command.usage);
+                    }
+                }
+            }
+            None => {
+                let _ = writeln!(output, "unknown command: {} (try help)", name);
+            }
+        }
+    }
+
+    // Completes the command name, or lists the candidates
+    fn complete(&mut self) {
+        let line = String::from(self.tty.line());
+        if line.contains(' ') {
+            return;
+        }
+        let mut candidates = matching_commands(&line);
+        let first = match candidates.next() {
+            Some(command) => command.name,
+            None => return,
+        };
+        // Longest prefix shared by every candidate
+        let mut common = first;
+        let mut count = 1;
+        for command in candidates {
+            count += 1;
+            let shared = common
+                .chars()
+                .zip(command.name.chars())
+                .take_while(|(a, b)| a == b)
+                .count();
+            common = &common[..shared];
+        }
+
+        if count == 1 {
+            self.tty.set_line(&alloc::format!("{} ", first));
+        } else if common.len() > line.len() {
+            self.tty.set_line(common);
+        } else {
+            let mut output = self.tty.output();
+            let _ = writeln!(output);
+            for command in matching_commands(&line) {
+                let _ = write!(output, "{}  ", command.name);
+            }
+            let _ = write!(output, "\n{}{}", PROMPT, line);
+        }
+    }
+}
+
+/// Runs a shell on `tty` forever
+pub async fn run(mut tty: Tty) {
+    tty.set_config(TtyConfig { edit_keys: true, ..TtyConfig::default() });
+    let mut shell = Shell {
+        tty,
+        history: Vec::new(),
+        history_position: 0,
+    };
+    shell.prompt();
+    while let Some(event) = shell.tty.next().await {
+        shell.handle(event);
+    }
+}
+
+fn help(output: &mut TtyOutput, _args: &[&str]) -> CommandResult {
+    for command in COMMANDS {
+        let _ = writeln!(output, "{:<9} {:<15} {}",
+                         command.name, command.usage, command.help);
+    }
+    Ok(())
+}
+
+fn meminfo(output: &mut TtyOutput, _args: &[&str]) -> CommandResult {
+    match &*memory::FRAME_ALLOCATOR.lock() {
+        Some(frames) => {
+            let stats = frames.stats();
+            let _ = writeln!(output, "frames: {} used, {} free, {} total ({} KiB free)",
+                             stats.used, stats.free, stats.total, stats.free * 4);
+        }
+        None => {
+            let _ = writeln!(output, "frames: allocator not set up");
+        }
+    }
+    if let Some(buddy) = &*memory::BUDDY_ALLOCATOR.lock() {
+        let stats = buddy.stats();
+        let _ = writeln!(output, "contiguous pool: {} frames used, {} free",
+                         stats.used, stats.free);
+    }
+    let (used, size) = allocator::heap_usage();
+    let _ = writeln!(output, "heap: {} bytes used, {} free, {} mapped, {} limit, {} resizes skipped",
+                     used, size - used, size, allocator::heap_limit(),
+                     allocator::skipped_resizes());
+    Ok(())
+}
+
+fn heapstat(output: &mut TtyOutput, _args: &[&str]) -> CommandResult {
+    let stats = allocator::heap_stats();
+    let _ = writeln!(output, "{} bytes used, {} peak, {} live, {} allocations, {} frees, {} failed",
+                     stats.in_use, stats.peak, stats.live(), stats.allocations,
+                     stats.frees, stats.failures);
+    for (bucket, &count) in stats.histogram.iter().enumerate() {
+        let _ = match allocator::tracking::bucket_limit(bucket) {
+            Some(limit) => writeln!(output, "<= {:>6}: {}", limit, count),
+            None => writeln!(output, " > {:>6}: {}", 8 << (bucket - 1), count),
+        };
+    }
+    Ok(())
+}
+
+fn slabinfo(output: &mut TtyOutput, _args: &[&str]) -> CommandResult {
+    let _ = writeln!(output, "{:<16} {:>6} {:>6} {:>6} {:>5} {:>7} {:>5}",
+                     "name", "active", "total", "size", "full", "partial", "empty");
+    memory::slab::for_each_cache(|cache| {
+        let stats = cache.stats();
+        let _ = writeln!(output, "{:<16} {:>6} {:>6} {:>6} {:>5} {:>7} {:>5}",
+                         cache.name(), stats.active_objects, stats.total_objects,
+                         stats.object_size, stats.full_slabs, stats.partial_slabs,
+                         stats.empty_slabs);
+    });
+    Ok(())
+}
+
+fn irqstat(output: &mut TtyOutput, _args: &[&str]) -> CommandResult {
+    interrupts::write_irq_stats(output).map_err(|_| String::from("write failed"))?;
+    let _ = writeln!(output, "dropped: {} scancodes, {} mouse events, {} serial bytes, {} wakes",
+                     keyboard::dropped_scancodes(), mouse::dropped_events(),
+                     serial::dropped_input(), executor::dropped_wakes());
+    Ok(())
+}
+
+fn uptime(output: &mut TtyOutput, _args: &[&str]) -> CommandResult {
+    let uptime = interrupts::uptime();
+    let _ = writeln!(output, "up {}.{:03} s ({} ticks)",
+                     uptime.as_secs(), uptime.subsec_millis(), interrupts::ticks());
+    Ok(())
+}
+
+fn pagewalk(output: &mut TtyOutput, args: &[&str]) -> CommandResult {
+    let addr = VirtAddr::try_new(parse_arg(args, 0)?)
+        .map_err(|_| String::from("not a canonical address"))?;
+    let phys = memory::walk_page_tables(addr, |level, entry| {
+        let _ = writeln!(output, "P{}: {:#x} {:?}",
+                         level, entry.addr().as_u64(), entry.flags());
+    });
+    match phys {
+        Some(phys) => {
+            let _ = writeln!(output, "{:#x} -> {:#x}", addr.as_u64(), phys.as_u64());
+        }
+        None => {
+            let _ = writeln!(output, "{:#x} is not mapped", addr.as_u64());
+        }
+    }
+    Ok(())
+}
+
+fn ptdump(output: &mut TtyOutput, args: &[&str]) -> CommandResult {
+    use memory::dump::{self, Violation};
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_frame, _) = Cr3::read();
+    match args.first() {
+        None => dump::dump(level_4_frame, output).map_err(|_| String::from("write failed")),
+        Some(&"check") => {
+            let count = dump::check(level_4_frame, |violation| {
+                let (problem, range) = match violation {
+                    Violation::WritableExecutable(range) => ("writable and executable", range),
+                    Violation::UserOutsideUserRegion(range) => ("user page in the kernel", range),
+                };
+                let _ = writeln!(output, "{}: {}", problem, range);
+            });
+            let _ = writeln!(output, "{} problems", count);
+            Ok(())
+        }
+        Some(arg) => Err(alloc::format!("unknown argument: {}", arg)),
+    }
+}
+
+// Checks that `addr` is mapped, and writable if asked, before touching it
+fn check_access(addr: u64, write: bool) -> Result<*mut u64, String> {
+    if addr % 8 != 0 {
+        return Err(String::from("address must be 8 byte aligned"));
+    }
+    let addr = VirtAddr::try_new(addr)
+        .map_err(|_| String::from("not a canonical address"))?;
+    let mut writable = true;
+    let mapped = memory::walk_page_tables(addr, |_, entry| {
+        writable &= entry.flags().contains(PageTableFlags::WRITABLE);
+    });
+    match mapped {
+        None => Err(alloc::format!("{:#x} is not mapped", addr.as_u64())),
+        Some(_) if write && !writable => {
+            Err(alloc::format!("{:#x} is read only", addr.as_u64()))
+        }
+        Some(_) => Ok(addr.as_mut_ptr()),
+    }
+}
+
+// Most words peek prints at once
+const MAX_PEEK_WORDS: u64 = 512;
+
+fn peek(output: &mut TtyOutput, args: &[&str]) -> CommandResult {
+    let addr = parse_arg(args, 0)?;
+    let count = if args.len() > 1 { parse_arg(args, 1)? } else { 1 };
+    if count > MAX_PEEK_WORDS {
+        return Err(alloc::format!("at most {} words at a time", MAX_PEEK_WORDS));
+    }
+    for i in 0..count {
+        let addr = i.checked_mul(8)
+            .and_then(|offset| addr.checked_add(offset))
+            .ok_or_else(|| String::from("range reaches past the end of the address space"))?;
+        let ptr = check_access(addr, false)?;
+        let value = unsafe { ptr.read_volatile() };
+        let _ = writeln!(output, "{:#018x}: {:#018x}", addr, value);
+    }
+    Ok(())
+}
+
+fn poke(_output: &mut TtyOutput, args: &[&str]) -> CommandResult {
+    let ptr = check_access(parse_arg(args, 0)?, true)?;
+    let value = parse_arg(args, 1)?;
+    unsafe { ptr.write_volatile(value) };
+    Ok(())
+}
+
+fn reboot(_output: &mut TtyOutput, _args: &[&str]) -> CommandResult {
+    power::reboot()
+}
+
+fn shutdown(_output: &mut TtyOutput, _args: &[&str]) -> CommandResult {
+    power::power_off()
+}
+
+fn clear(output: &mut TtyOutput, _args: &[&str]) -> CommandResult {
+    match output {
+        TtyOutput::Console => vga_buffer::clear_screen(),
+        // ANSI: erase the display and move home
+        TtyOutput::Serial => {
+            let _ = output.write_str("\x1b[2J\x1b[H");
+        }
+        TtyOutput::None => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_parse_number() {
+    serial_print!("test_parse_number... ");
+    assert_eq!(parse_number("42"), Some(42));
+    assert_eq!(parse_number("0xb8000"), Some(0xb8000));
+    assert_eq!(parse_number("0xg"), None);
+    assert_eq!(parse_number("-1"), None);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_matching_commands() {
+    serial_print!("test_matching_commands... ");
+    assert_eq!(matching_commands("p").count(), 4);
+    assert_eq!(matching_commands("up").next().map(|c| c.name), Some("uptime"));
+    assert!(matching_commands("x").next().is_none());
+    serial_println!("[ok]");
+}
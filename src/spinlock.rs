@@ -0,0 +1,266 @@
+// Interrupt-safe spin lock
+//
+// A plain spin::Mutex dead locks as soon as an interrupt handler tries to
+// take a lock held by the code it interrupted. IrqSpinLock disables
+// interrupts while the lock is held and restores the previous state of
+// RFLAGS.IF when the guard is dropped.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{spin_loop_hint, AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+
+#[cfg(debug_assertions)]
+use core::sync::atomic::AtomicUsize;
+
+// Number of spins after which a debug build assumes a dead lock
+#[cfg(debug_assertions)]
+const SPIN_LIMIT: usize = 100_000_000;
+
+// Number of held locks a debug build keeps track of
+#[cfg(debug_assertions)]
+const MAX_HELD_LOCKS: usize = 16;
+
+// Address and name of every held lock. This can't be an IrqSpinLock itself;
+// it's only touched with interrupts disabled.
+#[cfg(debug_assertions)]
+static HELD_LOCKS: spin::Mutex<[Option<(usize, &'static str)>; MAX_HELD_LOCKS]> =
+    spin::Mutex::new([None; MAX_HELD_LOCKS]);
+
+/// Calls `f` with the name of every IrqSpinLock that is currently held.
+///
+/// Locks are only tracked in debug builds. Returns false if the held locks
+/// are unknown, either because this is a release build or because the
+/// interrupted code was just updating them.
+#[cfg(debug_assertions)]
+pub fn for_each_held_lock(mut f: impl FnMut(&'static str)) -> bool {
+    match HELD_LOCKS.try_lock() {
+        Some(held) => {
+            held.iter().flatten().for_each(|&(_, name)| f(name));
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(not(debug_assertions))]
+pub fn for_each_held_lock(_f: impl FnMut(&'static str)) -> bool {
+    false
+}
+
+pub struct IrqSpinLock<T: ?Sized> {
+    // Used in dead lock reports
+    name: &'static str,
+    locked: AtomicBool,
+    // APIC ID + 1 of the CPU holding the lock, 0 if it's free
+    #[cfg(debug_assertions)]
+    owner: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for IrqSpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for IrqSpinLock<T> {}
+
+pub struct IrqSpinLockGuard<'a, T: ?Sized> {
+    lock: &'a IrqSpinLock<T>,
+    // Whether interrupts were enabled before the lock was taken
+    interrupts_enabled: bool,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(name: &'static str, data: T) -> Self {
+        IrqSpinLock {
+            name,
+            locked: AtomicBool::new(false),
+            #[cfg(debug_assertions)]
+            owner: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqSpinLock<T> {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Disables interrupts and spins until the lock is free.
+    ///
+    /// Debug builds panic with the lock's name instead of hanging when the
+    /// lock is already held by this CPU or can't be taken for too long.
+    pub fn lock(&self) -> IrqSpinLockGuard<T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        #[cfg(debug_assertions)]
+        let mut spins = 0;
+
+        while self.locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.is_locked() {
+                #[cfg(debug_assertions)]
+                self.check_dead_lock(&mut spins);
+                spin_loop_hint();
+            }
+        }
+
+        #[cfg(debug_assertions)]
+        self.track();
+
+        IrqSpinLockGuard { lock: self, interrupts_enabled }
+    }
+
+    /// Takes the lock if it's free, without spinning
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        if self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            #[cfg(debug_assertions)]
+            self.track();
+            Some(IrqSpinLockGuard { lock: self, interrupts_enabled })
+        } else {
+            if interrupts_enabled {
+                interrupts::enable();
+            }
+            None
+        }
+    }
+
+    /// Releases the lock without a guard.
+    ///
+    /// This is unsafe because the holder may still be using the data.
+    /// Only meant for panic paths that must print no matter what.
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(debug_assertions)]
+        self.untrack();
+        self.locked.store(false, Ordering::Release);
+    }
+
+    #[cfg(debug_assertions)]
+    fn address(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    // Records this CPU as the owner, called right after taking the lock
+    #[cfg(debug_assertions)]
+    fn track(&self) {
+        self.owner.store(cpu_id() + 1, Ordering::Relaxed);
+        let mut held = HELD_LOCKS.lock();
+        if let Some(slot) = held.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some((self.address(), self.name));
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn untrack(&self) {
+        self.owner.store(0, Ordering::Relaxed);
+        let address = self.address();
+        let mut held = HELD_LOCKS.lock();
+        if let Some(slot) = held
+            .iter_mut()
+            .find(|slot| slot.map(|(a, _)| a) == Some(address))
+        {
+            *slot = None;
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn check_dead_lock(&self, spins: &mut usize) {
+        // Interrupts are disabled, so nothing on this CPU can release it
+        if *spins == 0 && self.owner.load(Ordering::Relaxed) == cpu_id() + 1 {
+            dead_lock(self.name, "is already held by this CPU");
+        }
+        *spins += 1;
+        if *spins > SPIN_LIMIT {
+            dead_lock(self.name, "could not be taken in time");
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for IrqSpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for IrqSpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for IrqSpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        unsafe { self.lock.force_unlock() };
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+// Returns the initial APIC ID of the current CPU
+#[cfg(debug_assertions)]
+fn cpu_id() -> usize {
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    (cpuid.ebx >> 24) as usize
+}
+
+#[cfg(debug_assertions)]
+static DEAD_LOCK_REPORTED: AtomicBool = AtomicBool::new(false);
+
+// The panic handler prints, which may need the very lock that dead locked.
+// So report to the serial port without any lock first, and only panic once.
+#[cfg(debug_assertions)]
+fn dead_lock(name: &str, reason: &str) -> ! {
+    crate::serial::_emergency_print(
+        format_args!("\nDEAD LOCK: lock `{}` {}\n", name, reason));
+    if DEAD_LOCK_REPORTED.swap(true, Ordering::Relaxed) {
+        crate::hlt_loop();
+    }
+    panic!("dead lock on lock `{}`: {}", name, reason);
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_irq_spin_lock_restores_interrupts() {
+    serial_print!("test_irq_spin_lock_restores_interrupts... ");
+    let lock = IrqSpinLock::new("test", 0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(lock.is_locked());
+    }
+    assert!(interrupts::are_enabled());
+    assert!(!lock.is_locked());
+    assert_eq!(*lock.lock(), 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_irq_spin_lock_try_lock() {
+    serial_print!("test_irq_spin_lock_try_lock... ");
+    let lock = IrqSpinLock::new("test", ());
+    let guard = lock.try_lock();
+    assert!(guard.is_some());
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+    assert!(lock.try_lock().is_some());
+    assert!(interrupts::are_enabled());
+    serial_println!("[ok]");
+}
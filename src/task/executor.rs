@@ -0,0 +1,151 @@
+use super::{Task, TaskId};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use core::mem::ManuallyDrop;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use crossbeam_queue::ArrayQueue;
+use x86_64::instructions::interrupts;
+
+use crate::{watchdog, work_queue};
+
+// Number of tasks that can be ready at the same time
+const TASK_QUEUE_SIZE: usize = 100;
+
+// Wakes that found the task queue full. Wakers run in interrupt handlers,
+// so they count instead of panicking or printing.
+static DROPPED_WAKES: AtomicU64 = AtomicU64::new(0);
+
+/// Number of wakes lost so far
+pub fn dropped_wakes() -> u64 {
+    DROPPED_WAKES.load(Ordering::Relaxed)
+}
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    // IDs of the tasks that are ready to be polled. Shared with the wakers,
+    // which push to it from interrupt handlers.
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    // Tasks that were spawned but not polled yet. They have no waker that
+    // could queue them again, so they can't go through the bounded queue.
+    spawned: VecDeque<TaskId>,
+    waker_cache: BTreeMap<TaskId, Waker>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
+            spawned: VecDeque::new(),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        self.spawned.push_back(task_id);
+    }
+
+    /// Polls tasks forever, halting the CPU whenever no task is ready
+    pub fn run(&mut self) -> ! {
+        loop {
+            watchdog::touch();
+            work_queue::run_pending_work();
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        // Destructure to borrow the fields separately
+        let Self { tasks, task_queue, spawned, waker_cache } = self;
+
+        loop {
+            let task_id = match spawned.pop_front() {
+                Some(task_id) => task_id,
+                None => match task_queue.pop() {
+                    Ok(task_id) => task_id,
+                    Err(_) => break,
+                },
+            };
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                // The task finished already
+                None => continue,
+            };
+            let waker = waker_cache
+                .entry(task_id)
+                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+            let mut context = Context::from_waker(waker);
+            match task.poll(&mut context) {
+                Poll::Ready(()) => {
+                    tasks.remove(&task_id);
+                    waker_cache.remove(&task_id);
+                }
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // An interrupt between the check and the hlt could wake a task
+        // that we would then sleep through. So check with interrupts
+        // disabled and enable them atomically with the hlt.
+        interrupts::disable();
+        if self.task_queue.is_empty() && self.spawned.is_empty() {
+            crate::interrupts::enable_interrupts_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+}
+
+impl TaskWaker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+        let waker = Arc::new(TaskWaker { task_id, task_queue });
+        unsafe { Waker::from_raw(raw_waker(waker)) }
+    }
+
+    fn wake_task(&self) {
+        if self.task_queue.push(self.task_id).is_err() {
+            DROPPED_WAKES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// The Waker vtable. Each RawWaker owns one reference of an Arc<TaskWaker>.
+static VTABLE: RawWakerVTable =
+    RawWakerVTable::new(clone_waker, wake, wake_by_ref, drop_waker);
+
+fn raw_waker(waker: Arc<TaskWaker>) -> RawWaker {
+    RawWaker::new(Arc::into_raw(waker) as *const (), &VTABLE)
+}
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    // Borrow the reference of `data` without consuming it
+    let waker = ManuallyDrop::new(Arc::from_raw(data as *const TaskWaker));
+    raw_waker(Arc::clone(&*waker))
+}
+
+unsafe fn wake(data: *const ()) {
+    let waker = Arc::from_raw(data as *const TaskWaker);
+    waker.wake_task();
+}
+
+unsafe fn wake_by_ref(data: *const ()) {
+    let waker = ManuallyDrop::new(Arc::from_raw(data as *const TaskWaker));
+    waker.wake_task();
+}
+
+unsafe fn drop_waker(data: *const ()) {
+    drop(Arc::from_raw(data as *const TaskWaker));
+}
@@ -0,0 +1,106 @@
+// Input from interrupt handlers as a stream
+//
+// The handler pushes into a lock-free queue and wakes the task reading it.
+// The queue is allocated on the heap when the stream is opened, input that
+// arrives before that or while the queue is full is dropped. Handlers must
+// not block or print, so drops are only counted.
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use spin::Once;
+
+use super::{AtomicWaker, Stream};
+
+/// Queue between an interrupt handler and the one task reading it
+pub struct IrqQueue<T> {
+    name: &'static str,
+    size: usize,
+    queue: Once<ArrayQueue<T>>,
+    waker: AtomicWaker,
+    dropped: AtomicU64,
+}
+
+impl<T> IrqQueue<T> {
+    /// A queue holding up to `size` values once it is opened
+    pub const fn new(name: &'static str, size: usize) -> Self {
+        IrqQueue {
+            name,
+            size,
+            queue: Once::new(),
+            waker: AtomicWaker::new(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Called in interrupt context, so it doesn't block or allocate
+    pub fn push(&self, value: T) {
+        let pushed = match self.queue.r#try() {
+            Some(queue) => queue.push(value).is_ok(),
+            None => false,
+        };
+        if pushed {
+            self.waker.wake();
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of values lost so far
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Allocates the queue and returns its reader. There can only be one,
+    /// so this panics when called twice.
+    pub fn open(&'static self) -> IrqStream<T> {
+        if self.queue.r#try().is_some() {
+            panic!("{} queue opened twice", self.name);
+        }
+        self.queue.call_once(|| ArrayQueue::new(self.size));
+        IrqStream { queue: self }
+    }
+}
+
+/// The reading end of an `IrqQueue`
+pub struct IrqStream<T: 'static> {
+    queue: &'static IrqQueue<T>,
+}
+
+impl<T> Stream for IrqStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let irq_queue = self.queue;
+        let queue = irq_queue.queue.r#try().expect("queue not opened");
+
+        // Fast path without touching the waker
+        if let Ok(value) = queue.pop() {
+            return Poll::Ready(Some(value));
+        }
+
+        // Register first, then check again. A value pushed in between
+        // would otherwise be missed until the next one arrives.
+        irq_queue.waker.register(cx.waker());
+        match queue.pop() {
+            Ok(value) => {
+                irq_queue.waker.take();
+                Poll::Ready(Some(value))
+            }
+            Err(_) => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_drops_before_open() {
+    serial_print!("test_drops_before_open... ");
+    static QUEUE: IrqQueue<u8> = IrqQueue::new("test", 4);
+    QUEUE.push(1);
+    QUEUE.push(2);
+    assert_eq!(QUEUE.dropped(), 2);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,109 @@
+// Cooperative multitasking based on async/await
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use crate::spinlock::IrqSpinLock;
+
+pub mod executor;
+pub mod irq_stream;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+pub struct Task {
+    id: TaskId,
+    // Pinned because async blocks may reference themselves
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+/// An asynchronous sequence of values, the async version of `Iterator`
+pub trait Stream {
+    type Item;
+
+    /// Returns the next value if one is ready. Otherwise returns
+    /// `Poll::Pending` and wakes the waker in `cx` once one is.
+    /// `Poll::Ready(None)` means the stream has ended.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Option<Self::Item>>;
+}
+
+/// Adapters for streams, so that `stream.next().await` works
+pub trait StreamExt: Stream {
+    fn next(&mut self) -> Next<Self> where Self: Unpin {
+        Next { stream: self }
+    }
+}
+
+impl<S: Stream + ?Sized> StreamExt for S {}
+
+/// Future returned by `StreamExt::next`
+pub struct Next<'a, S: ?Sized> {
+    stream: &'a mut S,
+}
+
+impl<'a, S: Stream + Unpin + ?Sized> Future for Next<'a, S> {
+    type Output = Option<S::Item>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut *self.stream).poll_next(cx)
+    }
+}
+
+/// Holds the waker of a task waiting for an interrupt.
+///
+/// The task registers its waker before going to sleep, the interrupt
+/// handler wakes it.
+pub struct AtomicWaker {
+    waker: IrqSpinLock<Option<Waker>>,
+}
+
+impl AtomicWaker {
+    pub const fn new() -> Self {
+        AtomicWaker {
+            waker: IrqSpinLock::new("atomic_waker", None),
+        }
+    }
+
+    pub fn register(&self, waker: &Waker) {
+        let mut slot = self.waker.lock();
+        match &*slot {
+            Some(old) if old.will_wake(waker) => {}
+            _ => *slot = Some(waker.clone()),
+        }
+    }
+
+    /// Removes the registered waker without waking it
+    pub fn take(&self) -> Option<Waker> {
+        self.waker.lock().take()
+    }
+
+    pub fn wake(&self) {
+        // Wake outside of the lock, waking may take other locks
+        if let Some(waker) = self.take() {
+            waker.wake();
+        }
+    }
+}
@@ -0,0 +1,376 @@
+// Terminal line discipline
+//
+// Sits between an input device (the keyboard or COM1) and its consumer.
+// In canonical mode it collects a line with basic editing and hands it
+// out on enter, in raw mode every character is passed through. Echo goes
+// back to the device the input came from.
+use alloc::string::String;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use crate::keyboard::KeyStream;
+use crate::keymap::{DecodedKey, KeyCode};
+use crate::serial::{self, SerialStream};
+use crate::task::Stream;
+use crate::{print, serial_print};
+
+// Control characters
+const CTRL_C: char = '\x03';
+const CTRL_D: char = '\x04';
+const BACKSPACE: char = '\x08';
+const CTRL_U: char = '\x15';
+const CTRL_W: char = '\x17';
+const CTRL_Z: char = '\x1a';
+const ESCAPE: char = '\x1b';
+const DELETE: char = '\x7f';
+
+/// What a terminal hands to its reader
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TtyEvent {
+    /// A complete line without the newline (canonical mode)
+    Line(String),
+    /// A single character (raw mode)
+    Char(char),
+    /// Ctrl-C
+    Interrupt,
+    /// Ctrl-D on an empty line
+    EndOfFile,
+    /// Ctrl-Z
+    Suspend,
+    /// Arrow up (with `edit_keys`)
+    HistoryUp,
+    /// Arrow down (with `edit_keys`)
+    HistoryDown,
+    /// Tab (with `edit_keys`)
+    Complete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TtyConfig {
+    /// Collect and edit whole lines instead of passing on characters
+    pub canonical: bool,
+    /// Write input back to the device
+    pub echo: bool,
+    /// Turn Ctrl-C, Ctrl-D and Ctrl-Z into events
+    pub signals: bool,
+    /// Hand tab and the up and down arrows to the reader in canonical
+    /// mode, for history and completion
+    pub edit_keys: bool,
+}
+
+impl TtyConfig {
+    /// Every character is passed on as is
+    pub const RAW: TtyConfig = TtyConfig {
+        canonical: false,
+        echo: false,
+        signals: false,
+        edit_keys: false,
+    };
+}
+
+impl Default for TtyConfig {
+    fn default() -> Self {
+        TtyConfig {
+            canonical: true,
+            echo: true,
+            signals: true,
+            edit_keys: false,
+        }
+    }
+}
+
+/// Where a terminal writes its echo and output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtyOutput {
+    Console,
+    Serial,
+    /// Output is thrown away
+    None,
+}
+
+use core::fmt::{self, Write};
+
+impl fmt::Write for TtyOutput {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match *self {
+            TtyOutput::Console => print!("{}", s),
+            TtyOutput::Serial => {
+                // Serial terminals need a carriage return to go back to
+                // the start of the line
+                for (i, part) in s.split('\n').enumerate() {
+                    if i > 0 {
+                        serial_print!("\r\n");
+                    }
+                    serial_print!("{}", part);
+                }
+            }
+            TtyOutput::None => {}
+        }
+        Ok(())
+    }
+}
+
+// Progress through an ANSI escape sequence from a serial terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    // Got ESC
+    Start,
+    // Got ESC [
+    Csi,
+}
+
+/// Line editing and echo for one terminal, independent of the device
+pub struct LineDiscipline {
+    config: TtyConfig,
+    line: String,
+    output: TtyOutput,
+    escape: Escape,
+}
+
+impl LineDiscipline {
+    pub fn new(output: TtyOutput) -> Self {
+        LineDiscipline {
+            config: TtyConfig::default(),
+            line: String::new(),
+            output,
+            escape: Escape::None,
+        }
+    }
+
+    pub fn config(&self) -> TtyConfig {
+        self.config
+    }
+
+    /// Changes the mode. Leaving canonical mode drops a partly typed line.
+    pub fn set_config(&mut self, config: TtyConfig) {
+        if !config.canonical {
+            self.line.clear();
+        }
+        self.config = config;
+    }
+
+    pub fn output(&self) -> TtyOutput {
+        self.output
+    }
+
+    /// The partly typed line in canonical mode
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// Replaces the partly typed line, e.g. with one from the history
+    pub fn set_line(&mut self, line: &str) {
+        self.erase(self.line.chars().count());
+        self.line.push_str(line);
+        self.echo_str(line);
+    }
+
+    /// Feeds a key without a character, like the arrow keys
+    pub fn input_key(&mut self, key: KeyCode) -> Option<TtyEvent> {
+        if !(self.config.canonical && self.config.edit_keys) {
+            return None;
+        }
+        match key {
+            KeyCode::ArrowUp => Some(TtyEvent::HistoryUp),
+            KeyCode::ArrowDown => Some(TtyEvent::HistoryDown),
+            _ => None,
+        }
+    }
+
+    // Turns the escape sequences for the arrow keys into keys. The flag
+    // tells whether `c` was part of a sequence.
+    fn input_escape(&mut self, c: char) -> (bool, Option<TtyEvent>) {
+        match (self.escape, c) {
+            (Escape::None, ESCAPE) => self.escape = Escape::Start,
+            (Escape::None, _) => return (false, None),
+            (Escape::Start, '[') => self.escape = Escape::Csi,
+            (Escape::Csi, c) => {
+                self.escape = Escape::None;
+                let key = match c {
+                    'A' => KeyCode::ArrowUp,
+                    'B' => KeyCode::ArrowDown,
+                    'C' => KeyCode::ArrowRight,
+                    'D' => KeyCode::ArrowLeft,
+                    _ => return (true, None),
+                };
+                return (true, self.input_key(key));
+            }
+            // Not a sequence we know, drop it
+            (Escape::Start, _) => self.escape = Escape::None,
+        }
+        (true, None)
+    }
+
+    /// Feeds one input character, returns an event once there is one
+    pub fn input(&mut self, c: char) -> Option<TtyEvent> {
+        if self.config.signals {
+            match c {
+                CTRL_C => {
+                    self.line.clear();
+                    self.echo_str("^C\n");
+                    return Some(TtyEvent::Interrupt);
+                }
+                CTRL_Z => {
+                    self.line.clear();
+                    self.echo_str("^Z\n");
+                    return Some(TtyEvent::Suspend);
+                }
+                CTRL_D if !self.config.canonical || self.line.is_empty() => {
+                    return Some(TtyEvent::EndOfFile);
+                }
+                _ => {}
+            }
+        }
+
+        if !self.config.canonical {
+            self.echo(c);
+            return Some(TtyEvent::Char(c));
+        }
+
+        // Serial terminals send a carriage return for enter and delete
+        // for backspace
+        let c = match c {
+            '\r' => '\n',
+            DELETE => BACKSPACE,
+            c => c,
+        };
+
+        let (escape, event) = self.input_escape(c);
+        if escape {
+            return event;
+        }
+
+        match c {
+            '\n' => {
+                self.echo('\n');
+                let line = core::mem::replace(&mut self.line, String::new());
+                return Some(TtyEvent::Line(line));
+            }
+            // Ctrl-D on a non-empty line passes it on without a newline
+            CTRL_D => {
+                let line = core::mem::replace(&mut self.line, String::new());
+                return Some(TtyEvent::Line(line));
+            }
+            BACKSPACE => self.erase(1),
+            CTRL_U => self.erase(self.line.chars().count()),
+            CTRL_W => {
+                let trimmed = self.line.trim_end_matches(' ');
+                let word_start = trimmed.rfind(' ').map_or(0, |i| i + 1);
+                let count = self.line[word_start..].chars().count();
+                self.erase(count);
+            }
+            '\t' if self.config.edit_keys => return Some(TtyEvent::Complete),
+            c if c.is_control() && c != '\t' => {}
+            c => {
+                self.line.push(c);
+                self.echo(c);
+            }
+        }
+        None
+    }
+
+    /// Removes the last `count` characters of the line
+    fn erase(&mut self, count: usize) {
+        for _ in 0..count {
+            if self.line.pop().is_none() {
+                break;
+            }
+            self.echo_str("\x08 \x08");
+        }
+    }
+
+    fn echo(&mut self, c: char) {
+        if self.config.echo {
+            let _ = self.output.write_char(c);
+        }
+    }
+
+    fn echo_str(&mut self, s: &str) {
+        if self.config.echo {
+            let _ = self.output.write_str(s);
+        }
+    }
+}
+
+enum TtyInput {
+    Keyboard(KeyStream),
+    Serial(SerialStream),
+}
+
+/// A terminal: an input device with a line discipline on top
+pub struct Tty {
+    input: TtyInput,
+    discipline: LineDiscipline,
+}
+
+impl Tty {
+    /// Keyboard input with echo on the VGA console. Takes the keyboard, so
+    /// there can only be one.
+    pub fn console() -> Self {
+        Tty {
+            input: TtyInput::Keyboard(KeyStream::new()),
+            discipline: LineDiscipline::new(TtyOutput::Console),
+        }
+    }
+
+    /// Input and echo on COM1. There can only be one.
+    pub fn serial() -> Self {
+        Tty {
+            input: TtyInput::Serial(serial::input_stream()),
+            discipline: LineDiscipline::new(TtyOutput::Serial),
+        }
+    }
+
+    pub fn config(&self) -> TtyConfig {
+        self.discipline.config()
+    }
+
+    pub fn set_config(&mut self, config: TtyConfig) {
+        self.discipline.set_config(config);
+    }
+
+    /// The device this terminal echoes to, for writing output
+    pub fn output(&self) -> TtyOutput {
+        self.discipline.output()
+    }
+
+    pub fn line(&self) -> &str {
+        self.discipline.line()
+    }
+
+    pub fn set_line(&mut self, line: &str) {
+        self.discipline.set_line(line);
+    }
+
+    fn poll_key(&mut self, cx: &mut Context) -> Poll<Option<DecodedKey>> {
+        match &mut self.input {
+            TtyInput::Keyboard(keys) => Pin::new(keys).poll_next(cx),
+            TtyInput::Serial(bytes) => Pin::new(bytes)
+                .poll_next(cx)
+                .map(|byte| byte.map(|byte| DecodedKey::Unicode(char::from(byte)))),
+        }
+    }
+}
+
+impl Stream for Tty {
+    type Item = TtyEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context)
+        -> Poll<Option<TtyEvent>> {
+        let this = self.get_mut();
+        loop {
+            let event = match this.poll_key(cx) {
+                Poll::Ready(Some(DecodedKey::Unicode(c))) => this.discipline.input(c),
+                Poll::Ready(Some(DecodedKey::RawKey(key))) => {
+                    this.discipline.input_key(key)
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Some(event) = event {
+                return Poll::Ready(Some(event));
+            }
+        }
+    }
+}
@@ -57,12 +57,28 @@ pub struct Writer {
     // reference to the VGA buffer.
     // 'static indicates that this field lives through the whole lifetime.
     buffer: &'static mut Buffer,
+
+    // row and column of the mouse cursor, drawn by inverting the cell
+    mouse_cursor: Option<(usize, usize)>,
 }
 
 impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            // backspace only moves within the current line
+            b'\x08' => {
+                if self.column_position > 0 {
+                    self.column_position -= 1;
+                    let row = BUFFER_HEIGHT - 1;
+                    let col = self.column_position;
+                    let color_code = self.color_code;
+                    self.buffer.chars[row][col].write(ScreenChar {
+                        ascii_character: b' ',
+                        color_code,
+                    });
+                }
+            }
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
@@ -85,7 +101,7 @@ impl Writer {
         for byte in s.bytes() {
             match byte {
                 // printable byte of newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                0x20..=0x7e | b'\n' | b'\x08' => self.write_byte(byte),
                 // non-printable range
                 _ => self.write_byte(0xfe),
             }
@@ -112,18 +128,48 @@ impl Writer {
             self.buffer.chars[row][col].write(blank);
         }
     }
+
+    /// Blanks the whole screen and starts over at the bottom line
+    pub fn clear_screen(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.column_position = 0;
+        // The cleared cell under the cursor isn't inverted anymore
+        self.toggle_mouse_cursor();
+    }
+
+    /// Moves the mouse cursor, or hides it with `None`
+    pub fn set_mouse_cursor(&mut self, position: Option<(usize, usize)>) {
+        let position = position
+            .map(|(row, col)| (row.min(BUFFER_HEIGHT - 1), col.min(BUFFER_WIDTH - 1)));
+        self.toggle_mouse_cursor();
+        self.mouse_cursor = position;
+        self.toggle_mouse_cursor();
+    }
+
+    // Swaps foreground and background color of the cell under the cursor.
+    // Doing it twice restores the cell.
+    fn toggle_mouse_cursor(&mut self) {
+        if let Some((row, col)) = self.mouse_cursor {
+            let mut character = self.buffer.chars[row][col].read();
+            character.color_code = ColorCode(character.color_code.0.rotate_left(4));
+            self.buffer.chars[row][col].write(character);
+        }
+    }
 }
 
 use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::spinlock::IrqSpinLock;
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER: IrqSpinLock<Writer> = IrqSpinLock::new("vga", Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         // This works because 0xb8000's physical address is same
         // as virtual address
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        mouse_cursor: None,
     });
 }
 
@@ -131,11 +177,28 @@ use core::fmt;
 
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Scrolling would move the inverted cell along with the text
+        self.toggle_mouse_cursor();
         self.write_string(s);
+        self.toggle_mouse_cursor();
         Ok(())
     }
 }
 
+/// Width and height of the screen in characters
+pub fn size() -> (usize, usize) {
+    (BUFFER_WIDTH, BUFFER_HEIGHT)
+}
+
+pub fn clear_screen() {
+    WRITER.lock().clear_screen();
+}
+
+/// Moves the text mode mouse cursor, or hides it with `None`
+pub fn set_mouse_cursor(position: Option<(usize, usize)>) {
+    WRITER.lock().set_mouse_cursor(position);
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
@@ -150,13 +213,8 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    // Avoid dead locks
-    // Otherwise, the timer interrupt handler can block
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    WRITER.lock().write_fmt(args).unwrap();
 }
 
 // Test
@@ -188,15 +246,13 @@ fn test_println_output() {
     let s = "Some test string that fits on a single line";
 
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-    
-    interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
-        writeln!(writer, "\n{}", s).expect("writeln failed");
-        for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(char::from(screen_char.ascii_character), c);
-        }
-    });
+
+    // The lock keeps the timer interrupt from printing in between
+    let mut writer = WRITER.lock();
+    writeln!(writer, "\n{}", s).expect("writeln failed");
+    for (i, c) in s.chars().enumerate() {
+        let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+        assert_eq!(char::from(screen_char.ascii_character), c);
+    }
     serial_println!("[ok]");
 }
\ No newline at end of file
@@ -0,0 +1,86 @@
+// Lockup watchdog
+//
+// The main context calls `touch` whenever it makes progress (the idle loop
+// does so on every wake up). The timer interrupt counts the ticks since the
+// last touch and reports a hung kernel once the count reaches the timeout.
+// An NMI reports the same way, which catches hangs with interrupts off.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::interrupts::{self, InterruptIndex};
+use crate::serial::_emergency_print;
+use crate::spinlock;
+
+// About 10 seconds at the PIT's default rate of 18.2 Hz
+pub const DEFAULT_TIMEOUT_TICKS: usize = 182;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TIMEOUT_TICKS: AtomicUsize = AtomicUsize::new(DEFAULT_TIMEOUT_TICKS);
+// Timer ticks since the main context last made progress
+static STALLED_TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Hooks the watchdog up to the timer interrupt and arms it
+pub fn init() {
+    interrupts::register_irq(InterruptIndex::Timer.irq(), timer_tick)
+        .expect("failed to register the watchdog");
+    enable();
+}
+
+/// Tells the watchdog that the main context is making progress
+pub fn touch() {
+    STALLED_TICKS.store(0, Ordering::Relaxed);
+}
+
+pub fn enable() {
+    touch();
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Sets how many timer ticks without progress count as a hang
+pub fn set_timeout(ticks: usize) {
+    touch();
+    TIMEOUT_TICKS.store(ticks, Ordering::Relaxed);
+}
+
+fn timer_tick(stack_frame: &mut InterruptStackFrame) {
+    let stalled = STALLED_TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    if ENABLED.load(Ordering::Relaxed)
+        && stalled >= TIMEOUT_TICKS.load(Ordering::Relaxed)
+    {
+        fire(stack_frame, "main context stopped making progress");
+    }
+}
+
+/// Dumps the interrupted context and panics.
+///
+/// Everything goes to the serial port without taking locks, because the
+/// hung code may hold them.
+pub fn fire(stack_frame: &InterruptStackFrame, reason: &str) -> ! {
+    disable();
+
+    _emergency_print(format_args!("\nWATCHDOG: {}\n", reason));
+    _emergency_print(format_args!(
+        "Interrupted RIP: {:?}\n", stack_frame.instruction_pointer));
+    _emergency_print(format_args!("{:#?}\n", stack_frame));
+
+    _emergency_print(format_args!("Held locks:"));
+    let mut held = 0;
+    let known = spinlock::for_each_held_lock(|name| {
+        held += 1;
+        _emergency_print(format_args!(" {}", name));
+    });
+    if !known {
+        _emergency_print(format_args!(" unknown\n"));
+    } else if held == 0 {
+        _emergency_print(format_args!(" none\n"));
+    } else {
+        _emergency_print(format_args!("\n"));
+    }
+
+    panic!("watchdog: kernel hung at {:?}", stack_frame.instruction_pointer);
+}
@@ -0,0 +1,166 @@
+// Deferred interrupt work ("bottom halves")
+//
+// Interrupt handlers should do as little as possible with interrupts
+// disabled. Anything slow (decoding, printing, ...) is queued here instead
+// and runs after the EOI with interrupts enabled, or from the idle loop.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+/// A small unit of deferred work: a function and its argument
+#[derive(Debug, Clone, Copy)]
+pub struct Work {
+    func: fn(usize),
+    data: usize,
+}
+
+impl Work {
+    pub fn new(func: fn(usize), data: usize) -> Self {
+        Work { func, data }
+    }
+
+    fn run(self) {
+        (self.func)(self.data)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull;
+
+// Must be a power of two so that the indexes can wrap around
+const QUEUE_SIZE: usize = 64;
+
+fn nop(_data: usize) {}
+
+// Ring buffer with one consumer (whoever holds RUNNING) and producers that
+// run with interrupts disabled. On a single CPU that makes producers
+// exclusive, so neither side ever needs a lock.
+struct WorkQueue {
+    slots: UnsafeCell<[Work; QUEUE_SIZE]>,
+    // Next slot to read, only written by the consumer
+    head: AtomicUsize,
+    // Next slot to write, only written by producers
+    tail: AtomicUsize,
+}
+
+unsafe impl Sync for WorkQueue {}
+
+impl WorkQueue {
+    fn push(&self, work: Work) -> Result<(), QueueFull> {
+        interrupts::without_interrupts(|| {
+            let tail = self.tail.load(Ordering::Relaxed);
+            let head = self.head.load(Ordering::Acquire);
+            if tail.wrapping_sub(head) == QUEUE_SIZE {
+                return Err(QueueFull);
+            }
+            unsafe {
+                (*self.slots.get())[tail % QUEUE_SIZE] = work;
+            }
+            self.tail.store(tail.wrapping_add(1), Ordering::Release);
+            Ok(())
+        })
+    }
+
+    // Only called by the holder of RUNNING
+    fn pop(&self) -> Option<Work> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let work = unsafe { (*self.slots.get())[head % QUEUE_SIZE] };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(work)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+}
+
+static QUEUE: WorkQueue = WorkQueue {
+    slots: UnsafeCell::new([Work { func: nop, data: 0 }; QUEUE_SIZE]),
+    head: AtomicUsize::new(0),
+    tail: AtomicUsize::new(0),
+};
+
+// Set while some context is draining the queue
+static RUNNING: AtomicBool = AtomicBool::new(false);
+// Work that was dropped because the queue was full
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Queues `func(data)` to run later with interrupts enabled.
+///
+/// Safe to call from interrupt handlers. If the queue is full the work is
+/// dropped and counted in `dropped_work`.
+pub fn queue_work(func: fn(usize), data: usize) -> Result<(), QueueFull> {
+    let result = QUEUE.push(Work::new(func, data));
+    if result.is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    result
+}
+
+/// Returns true if there is queued work that hasn't run yet
+pub fn has_pending_work() -> bool {
+    !QUEUE.is_empty()
+}
+
+/// Returns the number of work items dropped because the queue was full
+pub fn dropped_work() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Runs queued work in FIFO order until the queue is empty.
+///
+/// Only one context drains the queue at a time. A nested call, e.g. from
+/// an interrupt arriving while work runs, returns immediately and leaves
+/// its work to the outer call.
+pub fn run_pending_work() {
+    loop {
+        if RUNNING
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+
+        while let Some(work) = QUEUE.pop() {
+            work.run();
+        }
+        RUNNING.store(false, Ordering::Release);
+
+        // Work may have been queued by an interrupt that arrived after the
+        // last pop but saw RUNNING still set.
+        if QUEUE.is_empty() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[cfg(test)]
+static TEST_SUM: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(test)]
+fn add_to_test_sum(data: usize) {
+    TEST_SUM.fetch_add(data, Ordering::Relaxed);
+}
+
+#[test_case]
+fn test_run_pending_work() {
+    serial_print!("test_run_pending_work... ");
+    // Keep the timer interrupt from draining the queue before we check it
+    interrupts::without_interrupts(|| {
+        queue_work(add_to_test_sum, 1).unwrap();
+        queue_work(add_to_test_sum, 2).unwrap();
+        assert!(has_pending_work());
+    });
+    run_pending_work();
+    assert!(!has_pending_work());
+    assert_eq!(TEST_SUM.load(Ordering::Relaxed), 3);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,8 @@
+#!/bin/sh
+# Runs the heap allocation tests once for every heap backend
+set -e
+
+for backend in bump linked-list fixed-size-block; do
+    echo "heap-$backend:"
+    cargo xtest --test heap_allocation --no-default-features --features "heap-$backend"
+done
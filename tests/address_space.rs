@@ -0,0 +1,133 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(near_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use near_os::memory;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    near_os::init_for_tests(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    near_os::test_panic_handler(info)
+}
+
+use near_os::memory::address_space::activate_kernel;
+use near_os::memory::vmalloc::USER_REGION;
+use near_os::memory::{AddressSpace, VmError};
+use near_os::{serial_print, serial_println};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+fn user_start() -> VirtAddr {
+    VirtAddr::new(USER_REGION.start)
+}
+
+#[test_case]
+fn kernel_half_is_shared() {
+    serial_print!("kernel_half_is_shared... ");
+    let mut space = AddressSpace::new().expect("failed to create address space");
+    let code = VirtAddr::new(kernel_half_is_shared as usize as u64);
+    assert_eq!(space.translate(code), memory::translate(code));
+    assert!(space.translate(user_start()).is_none());
+    assert!(!space.is_active());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn map_outside_user_region() {
+    serial_print!("map_outside_user_region... ");
+    let mut space = AddressSpace::new().unwrap();
+    match space.map(VirtAddr::new(0x5555_0000_0000), 4096, PageTableFlags::WRITABLE) {
+        Err(VmError::OutsideRegion(_)) => {}
+        other => panic!("expected OutsideRegion, got {:?}", other),
+    }
+    let last_page = VirtAddr::new(USER_REGION.end() - 4096);
+    assert!(space.map(last_page, 2 * 4096, PageTableFlags::WRITABLE).is_err());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn switch_between_spaces() {
+    serial_print!("switch_between_spaces... ");
+    let before = memory::free_frames();
+    let mut a = AddressSpace::new().unwrap();
+    let mut b = AddressSpace::new().unwrap();
+    a.map(user_start(), 2 * 4096, PageTableFlags::WRITABLE).expect("map failed");
+    b.map(user_start(), 2 * 4096, PageTableFlags::WRITABLE).expect("map failed");
+    assert_ne!(a.translate(user_start()), b.translate(user_start()));
+    // Not visible in the kernel's own tables
+    assert!(memory::translate(user_start()).is_none());
+
+    let ptr: *mut u64 = user_start().as_mut_ptr();
+    unsafe {
+        a.activate();
+        assert!(a.is_active());
+        ptr.write_volatile(1);
+        b.activate();
+        ptr.write_volatile(2);
+        a.activate();
+        assert_eq!(ptr.read_volatile(), 1);
+        b.activate();
+        assert_eq!(ptr.read_volatile(), 2);
+        activate_kernel();
+    }
+    assert!(!b.is_active());
+
+    // Dropping the active space switches back to the kernel's tables
+    unsafe { a.activate() };
+    drop(a);
+    assert!(memory::translate(user_start()).is_none());
+    drop(b);
+    assert_eq!(memory::free_frames(), before);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn unmap_user_pages() {
+    serial_print!("unmap_user_pages... ");
+    let mut space = AddressSpace::new().unwrap();
+    space.map(user_start(), 4 * 4096, PageTableFlags::WRITABLE).unwrap();
+    let stats = space.unmap(user_start(), 4 * 4096).expect("unmap failed");
+    assert_eq!(stats.pages, 4);
+    // P3, P2 and P1 of the user region are not shared
+    assert_eq!(stats.tables_freed, 3);
+    assert!(space.translate(user_start()).is_none());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn kernel_mappings_made_while_active() {
+    serial_print!("kernel_mappings_made_while_active... ");
+    use near_os::memory::vmalloc::IOREMAP_REGION;
+
+    let space = AddressSpace::new().unwrap();
+    unsafe { space.activate() };
+    // Mapped in the kernel's tables after the space was created
+    let addr = VirtAddr::new(IOREMAP_REGION.end() - 4096);
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mut frames = memory::FRAME_ALLOCATOR.lock();
+        memory::map_range(mapper.as_mut().unwrap(), frames.as_mut().unwrap(), addr, 4096,
+                          PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+            .expect("map_range failed");
+    }
+    let ptr: *mut u64 = addr.as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(7);
+        assert_eq!(ptr.read_volatile(), 7);
+        activate_kernel();
+    }
+    drop(space);
+    serial_println!("[ok]");
+}
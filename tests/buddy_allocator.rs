@@ -0,0 +1,112 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(near_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use near_os::memory::{self, BitmapFrameAllocator, BuddyAllocator, Constraints};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    near_os::init();
+    unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, boot_info.physical_memory_offset)
+    };
+    let buddy_allocator = unsafe {
+        BuddyAllocator::init(&boot_info.memory_map, &mut frame_allocator,
+                             boot_info.physical_memory_offset,
+                             memory::CONTIGUOUS_POOL_SIZE)
+    };
+    *memory::BUDDY_ALLOCATOR.lock() = Some(buddy_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    near_os::test_panic_handler(info)
+}
+
+use near_os::{serial_print, serial_println};
+
+#[test_case]
+fn allocate_aligned_blocks() {
+    serial_print!("allocate_aligned_blocks... ");
+    let mut guard = memory::BUDDY_ALLOCATOR.lock();
+    let buddy = guard.as_mut().unwrap();
+    let before = buddy.stats();
+
+    for order in 0..4 {
+        let frame = buddy.allocate(order, Constraints::NONE).expect("pool exhausted");
+        assert_eq!(frame.start_address().as_u64() % (4096 << order), 0);
+        buddy.deallocate(frame, order);
+    }
+    let frame = buddy.allocate(0, Constraints::NONE.aligned(0x10_0000)).unwrap();
+    assert_eq!(frame.start_address().as_u64() % 0x10_0000, 0);
+    buddy.deallocate(frame, 0);
+
+    assert_eq!(buddy.stats(), before);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn allocate_below_limits() {
+    serial_print!("allocate_below_limits... ");
+    let mut guard = memory::BUDDY_ALLOCATOR.lock();
+    let buddy = guard.as_mut().unwrap();
+
+    let low = buddy.allocate(1, Constraints::BELOW_1MIB).expect("no memory below 1 MiB");
+    assert!(low.start_address().as_u64() + 2 * 4096 <= 0x10_0000);
+    let dma = buddy.allocate(2, Constraints::BELOW_4GIB).unwrap();
+    assert!(dma.start_address().as_u64() + 4 * 4096 <= 0x1_0000_0000);
+
+    buddy.deallocate(low, 1);
+    buddy.deallocate(dma, 2);
+    serial_println!("[ok]");
+}
+
+// Counts how many blocks of `order` can be allocated right now
+fn available_blocks(buddy: &mut BuddyAllocator, order: usize) -> usize {
+    let mut blocks = [None; 256];
+    let mut count = 0;
+    while count < blocks.len() {
+        match buddy.allocate(order, Constraints::NONE) {
+            Some(frame) => blocks[count] = Some(frame),
+            None => break,
+        }
+        count += 1;
+    }
+    for frame in blocks.iter().flatten() {
+        buddy.deallocate(*frame, order);
+    }
+    count
+}
+
+#[test_case]
+fn split_and_coalesce() {
+    serial_print!("split_and_coalesce... ");
+    let mut guard = memory::BUDDY_ALLOCATOR.lock();
+    let buddy = guard.as_mut().unwrap();
+    let before = buddy.stats();
+    let large_blocks = available_blocks(buddy, 4);
+
+    // Single frames split blocks once the small ones run out...
+    let mut frames = [None; 128];
+    for slot in frames.iter_mut() {
+        *slot = buddy.allocate(0, Constraints::NONE);
+    }
+    assert!(frames.iter().all(Option::is_some));
+
+    // ...and freeing them has to merge the buddies back together
+    for frame in frames.iter().flatten() {
+        buddy.deallocate(*frame, 0);
+    }
+    assert_eq!(available_blocks(buddy, 4), large_blocks);
+    assert_eq!(buddy.stats(), before);
+    serial_println!("[ok]");
+}
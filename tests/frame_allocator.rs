@@ -0,0 +1,104 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(near_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use near_os::memory::{self, BitmapFrameAllocator};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    near_os::init();
+    unsafe { memory::init(boot_info.physical_memory_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, boot_info.physical_memory_offset)
+    };
+    *memory::FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    near_os::test_panic_handler(info)
+}
+
+use near_os::{serial_print, serial_println};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::PhysAddr;
+
+#[test_case]
+fn allocate_distinct_frames() {
+    serial_print!("allocate_distinct_frames... ");
+    let mut guard = memory::FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let before = allocator.stats();
+
+    let mut frames = [None; 64];
+    for slot in frames.iter_mut() {
+        let frame = allocator.allocate_frame().expect("out of frames");
+        assert!(allocator.is_allocated(frame));
+        *slot = Some(frame);
+    }
+    for (i, a) in frames.iter().enumerate() {
+        assert!(frames[i + 1..].iter().all(|b| a != b));
+    }
+    assert_eq!(allocator.stats().used, before.used + frames.len());
+
+    for frame in frames.iter().flatten() {
+        allocator.deallocate_frame(*frame);
+    }
+    assert_eq!(allocator.stats(), before);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn reuse_freed_frame() {
+    serial_print!("reuse_freed_frame... ");
+    let mut guard = memory::FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let frame: PhysFrame = allocator.allocate_frame().unwrap();
+    allocator.deallocate_frame(frame);
+    assert!(!allocator.is_allocated(frame));
+    // The lowest free frame is handed out first
+    assert_eq!(allocator.allocate_frame(), Some(frame));
+    allocator.deallocate_frame(frame);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn exhaust_frames() {
+    serial_print!("exhaust_frames... ");
+    let mut guard = memory::FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let before = allocator.stats();
+    // Each frame stores the address of the one allocated before it, so
+    // they can all be freed again for the tests that follow. Addresses are
+    // stored plus one, so frame 0 doesn't look like the end of the list.
+    let mut last: Option<PhysFrame> = None;
+    let mut count = 0;
+    while let Some(frame) = allocator.allocate_frame() {
+        let link = frame.start_address().as_u64() + memory::physical_memory_offset();
+        unsafe { (link as *mut u64).write(last.map_or(0, |f| f.start_address().as_u64() + 1)) };
+        last = Some(frame);
+        count += 1;
+    }
+    assert_eq!(count, before.free);
+    assert_eq!(allocator.stats().free, 0);
+
+    while let Some(frame) = last {
+        let link = frame.start_address().as_u64() + memory::physical_memory_offset();
+        let previous = unsafe { (link as *const u64).read() };
+        allocator.deallocate_frame(frame);
+        last = match previous {
+            0 => None,
+            address => Some(PhysFrame::containing_address(PhysAddr::new(address - 1))),
+        };
+    }
+    assert_eq!(allocator.stats(), before);
+    serial_println!("[ok]");
+}
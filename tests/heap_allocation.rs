@@ -65,4 +65,93 @@ fn many_boxes() {
         assert_eq!(*x, i);
     }
     serial_println!("[ok]");
-}
\ No newline at end of file
+}
+
+// Every backend is checked on an arena of its own, whichever one the heap
+// is built with
+
+use alloc::alloc::Layout;
+use near_os::allocator::bump::BumpAllocator;
+use near_os::allocator::fixed_size_block::FixedSizeBlockAllocator;
+use near_os::allocator::linked_list::LinkedListAllocator;
+use near_os::allocator::HeapAllocator;
+
+const ARENA_SIZE: usize = 64 * 1024;
+
+#[repr(align(4096))]
+struct Arena([u8; ARENA_SIZE]);
+
+static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+fn arena_start() -> usize {
+    unsafe { ARENA.0.as_mut_ptr() as usize }
+}
+
+fn check_backend(backend: &mut impl HeapAllocator) {
+    let start = arena_start();
+    unsafe { backend.init(start, ARENA_SIZE) };
+    let in_arena = |ptr: *mut u8, size: usize| {
+        ptr as usize >= start && ptr as usize + size <= start + ARENA_SIZE
+    };
+
+    // Allocate and free
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let ptr = backend.allocate(layout);
+    assert!(!ptr.is_null() && in_arena(ptr, 24));
+    unsafe {
+        ptr.write_bytes(0xaa, 24);
+        backend.deallocate(ptr, layout);
+    }
+
+    // Freed memory is reused, or this would run out
+    for _ in 0..10_000 {
+        let ptr = backend.allocate(layout);
+        assert!(!ptr.is_null());
+        unsafe { backend.deallocate(ptr, layout) };
+    }
+
+    // Alignment
+    let mut held = [(core::ptr::null_mut(), layout); 6];
+    for (i, slot) in held.iter_mut().enumerate() {
+        let layout = Layout::from_size_align(8 << i, 8 << i).unwrap();
+        let ptr = backend.allocate(layout);
+        assert!(!ptr.is_null() && in_arena(ptr, layout.size()));
+        assert_eq!(ptr as usize % layout.align(), 0);
+        *slot = (ptr, layout);
+    }
+    for &(ptr, layout) in held.iter() {
+        unsafe { backend.deallocate(ptr, layout) };
+    }
+
+    // Larger than any block, and too large for the arena
+    let layout = Layout::from_size_align(ARENA_SIZE / 2, 4096).unwrap();
+    let ptr = backend.allocate(layout);
+    assert!(!ptr.is_null() && in_arena(ptr, layout.size()));
+    unsafe { backend.deallocate(ptr, layout) };
+    let layout = Layout::from_size_align(2 * ARENA_SIZE, 8).unwrap();
+    assert!(backend.allocate(layout).is_null());
+}
+
+#[test_case]
+fn bump_backend() {
+    serial_print!("bump_backend... ");
+    check_backend(&mut BumpAllocator::new());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn linked_list_backend() {
+    serial_print!("linked_list_backend... ");
+    let mut backend = LinkedListAllocator::new();
+    check_backend(&mut backend);
+    // Everything was freed, so the arena is one region again
+    assert_eq!(backend.free_regions(), (1, ARENA_SIZE));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn fixed_size_block_backend() {
+    serial_print!("fixed_size_block_backend... ");
+    check_backend(&mut FixedSizeBlockAllocator::new());
+    serial_println!("[ok]");
+}
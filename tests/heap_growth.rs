@@ -0,0 +1,93 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(near_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use near_os::allocator;
+use near_os::memory;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    near_os::init_for_tests(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    near_os::test_panic_handler(info)
+}
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use near_os::allocator::HEAP_SIZE;
+use near_os::{serial_print, serial_println};
+
+fn heap_size() -> usize {
+    allocator::heap_usage().1
+}
+
+#[test_case]
+fn grow_for_large_allocation() {
+    serial_print!("grow_for_large_allocation... ");
+    let before = memory::free_frames();
+    let mut buffer: Vec<u8> = Vec::with_capacity(4 * HEAP_SIZE);
+    buffer.resize(4 * HEAP_SIZE, 0xaa);
+    assert!(heap_size() > 4 * HEAP_SIZE);
+    assert!(buffer.iter().all(|&byte| byte == 0xaa));
+
+    drop(buffer);
+    // The chunk at the end is free again and gets unmapped
+    assert_eq!(heap_size(), HEAP_SIZE);
+    assert_eq!(memory::free_frames(), before);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn grow_for_many_allocations() {
+    serial_print!("grow_for_many_allocations... ");
+    let boxes: Vec<Box<[u64; 128]>> = (0..1000).map(|i| Box::new([i; 128])).collect();
+    assert!(heap_size() > HEAP_SIZE);
+    for (i, value) in boxes.iter().enumerate() {
+        assert_eq!(value[127], i as u64);
+    }
+    drop(boxes);
+    // One empty chunk of the usual 64 KiB stays mapped
+    assert_eq!(heap_size(), HEAP_SIZE + 64 * 1024);
+
+    let size = heap_size();
+    let before = memory::free_frames();
+    for _ in 0..100 {
+        let buffer: Vec<u8> = Vec::with_capacity(HEAP_SIZE / 2);
+        drop(buffer);
+        assert_eq!((heap_size(), memory::free_frames()), (size, before));
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn limit_stops_growth() {
+    serial_print!("limit_stops_growth... ");
+    let limit = allocator::heap_limit();
+    allocator::set_heap_limit(HEAP_SIZE + 256 * 1024);
+
+    let layout = Layout::from_size_align(1024 * 1024, 8).unwrap();
+    let ptr = unsafe { alloc(layout) };
+    assert!(ptr.is_null());
+    // Smaller allocations still fit under the limit
+    let layout = Layout::from_size_align(128 * 1024, 8).unwrap();
+    let ptr = unsafe { alloc(layout) };
+    assert!(!ptr.is_null());
+    unsafe { dealloc(ptr, layout) };
+
+    allocator::set_heap_limit(limit);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(near_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    near_os::init_for_tests(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    near_os::test_panic_handler(info)
+}
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use near_os::allocator::{self, tracking};
+use near_os::{serial_print, serial_println};
+
+#[test_case]
+fn counts_allocations() {
+    serial_print!("counts_allocations... ");
+    let before = allocator::heap_stats();
+    let value = Box::new([0u8; 100]);
+    let during = allocator::heap_stats();
+    assert_eq!(during.in_use, before.in_use + 100);
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert!(during.peak >= during.in_use);
+    // 100 bytes go in the up to 128 bytes bucket
+    assert_eq!(tracking::bucket_limit(4), Some(128));
+    assert_eq!(during.histogram[4], before.histogram[4] + 1);
+
+    drop(value);
+    let after = allocator::heap_stats();
+    assert_eq!(after.in_use, before.in_use);
+    assert_eq!(after.frees, before.frees + 1);
+    assert_eq!(after.live(), before.live());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn no_leaks() {
+    serial_print!("no_leaks... ");
+    let sum = allocator::assert_no_leaks(|| {
+        let values: Vec<u64> = (0..100).collect();
+        values.iter().sum::<u64>()
+    });
+    assert_eq!(sum, 4950);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn finds_leak() {
+    serial_print!("finds_leak... ");
+    tracking::start_leak_tracking();
+    let freed = Box::new(1u64);
+    let leaked: *mut u64 = Box::leak(Box::new(2u64));
+    drop(freed);
+    let summary = tracking::stop_leak_tracking();
+    assert_eq!((summary.count, summary.bytes, summary.untracked), (1, 8, 0));
+
+    let mut found = 0;
+    tracking::for_each_leak(|leak| {
+        assert_eq!(leak.ptr, leaked as usize);
+        assert!(leak.callers[0] != 0);
+        found += 1;
+    });
+    assert_eq!(found, 1);
+    unsafe { drop(Box::from_raw(leaked)) };
+    serial_println!("[ok]");
+}
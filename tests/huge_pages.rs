@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(near_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use near_os::memory::{self, BitmapFrameAllocator};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    near_os::init();
+    let mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, boot_info.physical_memory_offset)
+    };
+    *memory::MAPPER.lock() = Some(mapper);
+    *memory::FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    near_os::test_panic_handler(info)
+}
+
+use near_os::{serial_print, serial_println};
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+fn flags() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+}
+
+// Level of the last page table entry used to translate `addr`
+fn mapping_level(addr: VirtAddr) -> u8 {
+    let mut last = 0;
+    memory::walk_page_tables(addr, |level, _| last = level);
+    last
+}
+
+#[test_case]
+fn translate_physical_memory_window() {
+    serial_print!("translate_physical_memory_window... ");
+    // The bootloader maps physical memory with huge pages
+    let offset = memory::physical_memory_offset();
+    let addr = VirtAddr::new(offset + 0xb8123);
+    assert_eq!(memory::translate(addr), Some(PhysAddr::new(0xb8123)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn map_huge_pages() {
+    serial_print!("map_huge_pages... ");
+    let mut mapper = memory::MAPPER.lock();
+    let mapper = mapper.as_mut().unwrap();
+    let mut guard = memory::FRAME_ALLOCATOR.lock();
+    let frame_allocator = guard.as_mut().unwrap();
+
+    // 2 MiB aligned, plus a 4 KiB tail
+    let start = VirtAddr::new(0x5555_0000_0000);
+    let size = 2 * 1024 * 1024 + 4096;
+    memory::map_range(mapper, frame_allocator, start, size, flags())
+        .expect("map_range failed");
+
+    assert_eq!(mapping_level(start), 2);
+    assert_eq!(mapping_level(start + 2 * 1024 * 1024u64), 1);
+    let phys = memory::translate(start).unwrap();
+    assert_eq!(phys.as_u64() % (2 * 1024 * 1024), 0);
+    assert_eq!(memory::translate(start + 0x1234u64), Some(phys + 0x1234u64));
+
+    let ptr: *mut u64 = (start + 0x10_0000u64).as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+
+    // Physical range that is 2 MiB aligned as well
+    let frame: PhysFrame<x86_64::structures::paging::Size2MiB> =
+        x86_64::structures::paging::FrameAllocator::allocate_frame(frame_allocator).unwrap();
+    let virt = VirtAddr::new(0x5555_4000_0000);
+    unsafe {
+        memory::map_physical_range(mapper, frame_allocator, virt,
+                                   frame.start_address(), 2 * 1024 * 1024, flags())
+            .expect("map_physical_range failed");
+    }
+    assert_eq!(mapping_level(virt), 2);
+    assert_eq!(memory::translate(virt + 0x1000u64), Some(frame.start_address() + 0x1000u64));
+    serial_println!("[ok]");
+}
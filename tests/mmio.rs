@@ -0,0 +1,66 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(near_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use near_os::memory;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    near_os::init_for_tests(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    near_os::test_panic_handler(info)
+}
+
+use near_os::mmio::{self, CacheMode};
+use near_os::{serial_print, serial_println};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::PhysAddr;
+
+const VGA_BUFFER: u64 = 0xb8000;
+// Last cell of the bottom line, the tests print on the serial port only
+const LAST_CELL: usize = (25 * 80 - 1) * 2;
+
+#[test_case]
+fn uncached_registers() {
+    serial_print!("uncached_registers... ");
+    let mut vga = mmio::map::<[u16; 2000]>(PhysAddr::new(VGA_BUFFER), 4000)
+        .expect("mmio::map failed");
+    let flags = memory::leaf_flags(vga.base());
+    assert!(flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH));
+
+    vga.write_at::<u16>(LAST_CELL, 0x0f21);
+    assert_eq!(vga.read_at::<u16>(LAST_CELL), 0x0f21);
+    assert_eq!(vga.read()[LAST_CELL / 2], 0x0f21);
+    vga.write_at::<u16>(LAST_CELL, 0x0f20);
+
+    let base = vga.base();
+    drop(vga);
+    assert!(memory::translate(base).is_none());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn write_combining_frame_buffer() {
+    serial_print!("write_combining_frame_buffer... ");
+    let mut vga = mmio::map_with_mode::<u16>(PhysAddr::new(VGA_BUFFER), 4000,
+                                             CacheMode::WriteCombining)
+        .expect("mmio::map_with_mode failed");
+    assert!(!memory::leaf_flags(vga.base()).contains(PageTableFlags::NO_CACHE));
+    vga.write_at::<u16>(LAST_CELL, 0x0f2a);
+    assert_eq!(vga.read_at::<u16>(LAST_CELL), 0x0f2a);
+    vga.write_at::<u16>(LAST_CELL, 0x0f20);
+    serial_println!("[ok]");
+}
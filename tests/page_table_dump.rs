@@ -0,0 +1,154 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(near_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use near_os::memory;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    near_os::init_for_tests(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    near_os::test_panic_handler(info)
+}
+
+use core::fmt;
+use near_os::memory::dump::{self, Mapping, Violation};
+use near_os::memory::walk;
+use near_os::{serial_print, serial_println};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+const VGA_BUFFER: u64 = 0xb8000;
+
+// Maps `size` bytes of the VGA buffer at `virt`
+fn map_vga(virt: u64, size: u64, flags: PageTableFlags) {
+    let mut mapper = memory::MAPPER.lock();
+    let mut frames = memory::FRAME_ALLOCATOR.lock();
+    unsafe {
+        memory::map_physical_range(mapper.as_mut().unwrap(), frames.as_mut().unwrap(),
+                                   VirtAddr::new(virt), PhysAddr::new(VGA_BUFFER), size,
+                                   flags | PageTableFlags::PRESENT)
+            .expect("map_physical_range failed");
+    }
+}
+
+fn unmap(virt: u64, size: u64) {
+    let mut mapper = memory::MAPPER.lock();
+    let mut frames = memory::FRAME_ALLOCATOR.lock();
+    memory::unmap_range(mapper.as_mut().unwrap(), frames.as_mut().unwrap(),
+                        VirtAddr::new(virt), size, false)
+        .expect("unmap_range failed");
+}
+
+// map_to leaves USER_ACCESSIBLE off the tables above a page. Sets or
+// clears it on the entries leading to `virt`, as far as they exist.
+fn set_user_path(virt: u64, user: bool) {
+    let mut table = unsafe { walk::table_at(Cr3::read().0) };
+    for level in (2..=4u64).rev() {
+        let entry = &mut table[((virt >> (12 + 9 * (level - 1))) & 0x1ff) as usize];
+        let mut flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return;
+        }
+        flags.set(PageTableFlags::USER_ACCESSIBLE, user);
+        entry.set_flags(flags);
+        table = unsafe { walk::table_at(PhysFrame::containing_address(entry.addr())) };
+    }
+}
+
+fn ranges_in(start: u64, end: u64, mut visit: impl FnMut(Mapping)) {
+    dump::for_each_range(Cr3::read().0, |range| {
+        if (start..end).contains(&range.virt.as_u64()) {
+            visit(range);
+        }
+    });
+}
+
+struct LineCounter(usize);
+
+impl fmt::Write for LineCounter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.matches('\n').count();
+        Ok(())
+    }
+}
+
+#[test_case]
+fn merge_contiguous_pages() {
+    serial_print!("merge_contiguous_pages... ");
+    let start = 0x5800_0000_0000;
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | memory::no_execute();
+    map_vga(start, 4 * 4096, flags);
+
+    let mut pages = 0;
+    dump::for_each_page(Cr3::read().0, |page| {
+        if (start..start + 4 * 4096).contains(&page.virt.as_u64()) {
+            pages += 1;
+        }
+    });
+    assert_eq!(pages, 4);
+
+    let mut ranges = 0;
+    ranges_in(start, start + 4 * 4096, |range| {
+        ranges += 1;
+        assert_eq!(range.phys, PhysAddr::new(VGA_BUFFER));
+        assert_eq!(range.size, 4 * 4096);
+        assert!(range.writable());
+        assert!(range.flags.contains(PageTableFlags::NO_CACHE));
+    });
+    assert_eq!(ranges, 1);
+
+    let mut lines = LineCounter(0);
+    dump::dump(Cr3::read().0, &mut lines).expect("dump failed");
+    assert!(lines.0 > 2);
+    unmap(start, 4 * 4096);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn check_reports_violations() {
+    serial_print!("check_reports_violations... ");
+    let writable_executable = 0x5800_0001_0000;
+    let user = 0x5800_0002_0000;
+    map_vga(writable_executable, 4096, PageTableFlags::WRITABLE);
+    map_vga(user, 4096, PageTableFlags::USER_ACCESSIBLE | memory::no_execute());
+
+    // Not reachable from user mode while the tables above are supervisor
+    // only
+    dump::check(Cr3::read().0, |violation| {
+        if let Violation::UserOutsideUserRegion(range) = violation {
+            assert_ne!(range.virt.as_u64(), user);
+        }
+    });
+    set_user_path(user, true);
+
+    let mut found = [false; 2];
+    dump::check(Cr3::read().0, |violation| match violation {
+        Violation::WritableExecutable(range) if range.virt.as_u64() == writable_executable => {
+            found[0] = true;
+        }
+        Violation::UserOutsideUserRegion(range) if range.virt.as_u64() == user => {
+            found[1] = true;
+        }
+        // The rest of the tables are not under test
+        _ => {}
+    });
+    assert_eq!(found, [true, true]);
+
+    unmap(writable_executable, 4096);
+    unmap(user, 4096);
+    set_user_path(user, false);
+    serial_println!("[ok]");
+}
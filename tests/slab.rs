@@ -0,0 +1,136 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(near_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use near_os::memory;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    near_os::init_for_tests(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    near_os::test_panic_handler(info)
+}
+
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+use near_os::memory::slab;
+use near_os::memory::SlabCache;
+use near_os::{serial_print, serial_println};
+
+static SMALL: SlabCache = SlabCache::new("test_small", 24, 8, None);
+static ALIGNED: SlabCache = SlabCache::new("test_aligned", 100, 64, None);
+
+fn mark(object: *mut u8) {
+    unsafe { (object as *mut u64).write(0x5eed) };
+}
+
+static CONSTRUCTED: SlabCache = SlabCache::new("test_constructed", 8, 8, Some(mark));
+
+#[test_case]
+fn allocate_and_free() {
+    serial_print!("allocate_and_free... ");
+    let a = SMALL.allocate().unwrap();
+    let b = SMALL.allocate().unwrap();
+    assert_ne!(a, b);
+    assert!(b.as_ptr() as usize - a.as_ptr() as usize >= 24
+            || a.as_ptr() as usize - b.as_ptr() as usize >= 24);
+    let stats = SMALL.stats();
+    assert_eq!(stats.active_objects, 2);
+    assert_eq!(stats.partial_slabs, 1);
+
+    unsafe {
+        SMALL.deallocate(a);
+        SMALL.deallocate(b);
+    }
+    let stats = SMALL.stats();
+    assert_eq!(stats.active_objects, 0);
+    assert_eq!((stats.allocations, stats.frees), (2, 2));
+    assert_eq!((stats.partial_slabs, stats.empty_slabs), (0, 1));
+    // The free slab is reused
+    let c = SMALL.allocate().unwrap();
+    assert!(c == a || c == b);
+    unsafe { SMALL.deallocate(c) };
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn slab_lists() {
+    serial_print!("slab_lists... ");
+    let per_slab = ALIGNED.objects_per_slab();
+    let objects: Vec<NonNull<u8>> = (0..2 * per_slab + 1)
+        .map(|_| ALIGNED.allocate().unwrap())
+        .collect();
+    assert!(objects.iter().all(|object| object.as_ptr() as usize % 64 == 0));
+    let stats = ALIGNED.stats();
+    assert_eq!((stats.full_slabs, stats.partial_slabs, stats.empty_slabs), (2, 1, 0));
+    assert_eq!(stats.active_objects, 2 * per_slab + 1);
+    assert_eq!(stats.total_objects, 3 * per_slab);
+
+    let before = memory::free_frames();
+    for &object in objects.iter() {
+        unsafe { ALIGNED.deallocate(object) };
+    }
+    // One empty slab is kept, the others go back
+    let stats = ALIGNED.stats();
+    assert_eq!((stats.full_slabs, stats.partial_slabs, stats.empty_slabs), (0, 0, 1));
+    assert_eq!(memory::free_frames(), before + 2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn constructor_runs_once() {
+    serial_print!("constructor_runs_once... ");
+    let object = CONSTRUCTED.allocate().unwrap();
+    let value = object.as_ptr() as *mut u64;
+    unsafe {
+        assert_eq!(*value, 0x5eed);
+        value.write(7);
+        CONSTRUCTED.deallocate(object);
+    }
+    // Objects come back in the state they were freed in
+    let again = CONSTRUCTED.allocate().unwrap();
+    assert_eq!(again, object);
+    unsafe {
+        assert_eq!(*value, 7);
+        value.write(0x5eed);
+        CONSTRUCTED.deallocate(again);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn shrink_frees_empty_slabs() {
+    serial_print!("shrink_frees_empty_slabs... ");
+    // Caches only get registered once they are used, so both are used
+    // here instead of relying on earlier tests
+    let small = SMALL.allocate().unwrap();
+    let aligned = ALIGNED.allocate().unwrap();
+    unsafe {
+        SMALL.deallocate(small);
+        ALIGNED.deallocate(aligned);
+    }
+    let before = memory::free_frames();
+    let freed = slab::shrink_all();
+    assert!(freed >= 2);
+    assert_eq!(memory::free_frames(), before + freed);
+    assert_eq!(SMALL.stats().empty_slabs, 0);
+    assert_eq!(ALIGNED.stats().empty_slabs, 0);
+
+    let mut names = Vec::new();
+    slab::for_each_cache(|cache| names.push(cache.name()));
+    assert!(names.contains(&"test_small") && names.contains(&"test_aligned"));
+    serial_println!("[ok]");
+}
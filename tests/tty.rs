@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(near_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+// The line buffer lives on the heap
+fn main(boot_info: &'static BootInfo) -> ! {
+    near_os::init_for_tests(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    near_os::test_panic_handler(info)
+}
+
+use near_os::{serial_print, serial_println};
+use near_os::tty::{LineDiscipline, TtyConfig, TtyEvent, TtyOutput};
+
+fn feed(tty: &mut LineDiscipline, input: &str) -> Option<TtyEvent> {
+    input.chars().fold(None, |_, c| tty.input(c))
+}
+
+fn line(s: &str) -> Option<TtyEvent> {
+    Some(TtyEvent::Line(s.into()))
+}
+
+#[test_case]
+fn canonical_line_editing() {
+    serial_print!("canonical_line_editing... ");
+    let mut tty = LineDiscipline::new(TtyOutput::None);
+    assert_eq!(feed(&mut tty, "helo\x08lo\n"), line("hello"));
+    assert_eq!(feed(&mut tty, "rm -rf\x17\x17ls  \x17cat\n"), line("cat"));
+    // Serial terminals send carriage return and delete
+    assert_eq!(feed(&mut tty, "oops\x15ok\x7fk\r"), line("ok"));
+    assert_eq!(feed(&mut tty, "half\x04"), line("half"));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn control_events() {
+    serial_print!("control_events... ");
+    let mut tty = LineDiscipline::new(TtyOutput::None);
+    assert_eq!(feed(&mut tty, "half\x03"), Some(TtyEvent::Interrupt));
+    assert_eq!(feed(&mut tty, "\x04"), Some(TtyEvent::EndOfFile));
+    assert_eq!(feed(&mut tty, "x\x1a"), Some(TtyEvent::Suspend));
+    assert_eq!(feed(&mut tty, "\n"), line(""));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn raw_mode() {
+    serial_print!("raw_mode... ");
+    let mut tty = LineDiscipline::new(TtyOutput::None);
+    tty.set_config(TtyConfig::RAW);
+    assert_eq!(tty.input('a'), Some(TtyEvent::Char('a')));
+    assert_eq!(tty.input('\x03'), Some(TtyEvent::Char('\x03')));
+    // Carriage return and delete aren't translated either
+    assert_eq!(tty.input('\r'), Some(TtyEvent::Char('\r')));
+    assert_eq!(tty.input('\x7f'), Some(TtyEvent::Char('\x7f')));
+    tty.set_config(TtyConfig { canonical: false, ..TtyConfig::default() });
+    assert_eq!(tty.input('\x1a'), Some(TtyEvent::Suspend));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,109 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(near_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use near_os::memory;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    near_os::init_for_tests(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    near_os::test_panic_handler(info)
+}
+
+use near_os::memory::{UnmapRangeError, UnmapStats};
+use near_os::{serial_print, serial_println};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+const HUGE_PAGE: u64 = 2 * 1024 * 1024;
+
+fn flags() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+}
+
+fn map(start: VirtAddr, size: u64) {
+    let mut mapper = memory::MAPPER.lock();
+    let mut frames = memory::FRAME_ALLOCATOR.lock();
+    memory::map_range(mapper.as_mut().unwrap(), frames.as_mut().unwrap(), start, size, flags())
+        .expect("map_range failed");
+}
+
+fn unmap(start: VirtAddr, size: u64, free_frames: bool)
+    -> Result<UnmapStats, UnmapRangeError> {
+    let mut mapper = memory::MAPPER.lock();
+    let mut frames = memory::FRAME_ALLOCATOR.lock();
+    memory::unmap_range(mapper.as_mut().unwrap(), frames.as_mut().unwrap(),
+                        start, size, free_frames)
+}
+
+// Each test uses its own P4 entry, so all of its page tables are new
+
+#[test_case]
+fn unmap_and_free_frames() {
+    serial_print!("unmap_and_free_frames... ");
+    // A huge page and 64 small ones, enough to flush the whole TLB
+    let start = VirtAddr::new(0x5600_0000_0000);
+    let size = HUGE_PAGE + 64 * 4096;
+    let before = memory::free_frames();
+    map(start, size);
+    assert!(memory::translate(start + HUGE_PAGE).is_some());
+
+    let stats = unmap(start, size, true).expect("unmap_range failed");
+    assert_eq!(stats.pages, 65);
+    assert_eq!(stats.frames_freed, 512 + 64);
+    // P2 and P1, the P3 table is shared by all address spaces and stays
+    assert_eq!(stats.tables_freed, 2);
+    assert!(memory::translate(start).is_none());
+    assert!(memory::translate(start + HUGE_PAGE).is_none());
+    assert_eq!(memory::free_frames(), before - 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn unmap_keeps_device_frames() {
+    serial_print!("unmap_keeps_device_frames... ");
+    let start = VirtAddr::new(0x5680_0000_0000);
+    let before = memory::free_frames();
+    {
+        let mut mapper = memory::MAPPER.lock();
+        let mut frames = memory::FRAME_ALLOCATOR.lock();
+        unsafe {
+            memory::map_physical_range(mapper.as_mut().unwrap(), frames.as_mut().unwrap(),
+                                       start, PhysAddr::new(0xb8000), 4096, flags())
+                .expect("map_physical_range failed");
+        }
+    }
+    let stats = unmap(start, 4096, false).expect("unmap_range failed");
+    assert_eq!(stats.pages, 1);
+    assert_eq!(stats.frames_freed, 0);
+    assert_eq!(stats.tables_freed, 2);
+    assert_eq!(memory::free_frames(), before - 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn unmap_partial_huge_page() {
+    serial_print!("unmap_partial_huge_page... ");
+    let start = VirtAddr::new(0x5700_0000_0000);
+    map(start, HUGE_PAGE);
+    assert_eq!(unmap(start + 4096u64, 4096, true),
+               Err(UnmapRangeError::PartialHugePage(start)));
+    assert!(memory::translate(start).is_some());
+
+    let stats = unmap(start, HUGE_PAGE, true).expect("unmap_range failed");
+    assert_eq!(stats.pages, 1);
+    assert!(memory::translate(start).is_none());
+    serial_println!("[ok]");
+}
@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(near_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use near_os::memory;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    near_os::init_for_tests(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    near_os::test_panic_handler(info)
+}
+
+use near_os::memory::vmalloc::{self, Region, VirtualRangeAllocator};
+use near_os::{serial_print, serial_println};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+#[test_case]
+fn regions_do_not_overlap() {
+    serial_print!("regions_do_not_overlap... ");
+    vmalloc::check_regions();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn reserve_and_release_ranges() {
+    serial_print!("reserve_and_release_ranges... ");
+    let region = Region { name: "test", start: 0x1000_0000, size: 0x10_0000 };
+    let mut ranges = VirtualRangeAllocator::new(region);
+
+    let a = ranges.reserve(0x1000, 0).unwrap();
+    let b = ranges.reserve(0x2000, 0x1_0000).unwrap();
+    assert_eq!(a.as_u64(), region.start);
+    assert_eq!(b.as_u64() % 0x1_0000, 0);
+    assert!(region.contains(b));
+    assert!(ranges.reserve(region.size, 0).is_none());
+
+    assert_eq!(ranges.release(a), Some(0x1000));
+    assert_eq!(ranges.release(a), None);
+    assert_eq!(ranges.release(b), Some(0x2000));
+    // Everything merged back into one range
+    assert_eq!(ranges.reserve(region.size, 0).map(VirtAddr::as_u64), Some(region.start));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn vmalloc_and_vfree() {
+    serial_print!("vmalloc_and_vfree... ");
+    let size = 5 * 4096;
+    // The first allocation creates the region's P3 table, which stays
+    let warm_up = memory::vmalloc(size, PageTableFlags::WRITABLE).unwrap();
+    memory::vfree(warm_up).unwrap();
+
+    let before = memory::free_frames();
+    let start = memory::vmalloc(size, PageTableFlags::WRITABLE).expect("vmalloc failed");
+    assert!(vmalloc::VMALLOC_REGION.contains(start));
+    // Five pages plus whatever page tables were missing
+    assert!(memory::free_frames() <= before - 5);
+
+    let words = size / 8;
+    let ptr: *mut u64 = start.as_mut_ptr();
+    for i in 0..words {
+        unsafe { ptr.add(i).write_volatile(i as u64) };
+    }
+    for i in 0..words {
+        assert_eq!(unsafe { ptr.add(i).read_volatile() }, i as u64);
+    }
+    // The guard page stays unmapped
+    assert!(memory::translate(start + size as u64).is_none());
+
+    memory::vfree(start).expect("vfree failed");
+    assert!(memory::translate(start).is_none());
+    // The P2 and P1 tables are freed as well
+    assert_eq!(memory::free_frames(), before);
+    assert!(memory::vfree(start).is_err());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn ioremap_vga_buffer() {
+    serial_print!("ioremap_vga_buffer... ");
+    let addr = memory::ioremap(PhysAddr::new(0xb8010), 4000).expect("ioremap failed");
+    assert!(vmalloc::IOREMAP_REGION.contains(addr));
+    assert_eq!(addr.as_u64() % 4096, 0x10);
+    assert_eq!(memory::translate(addr), Some(PhysAddr::new(0xb8010)));
+    memory::iounmap(addr).expect("iounmap failed");
+    assert!(memory::translate(addr).is_none());
+    serial_println!("[ok]");
+}
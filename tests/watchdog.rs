@@ -0,0 +1,58 @@
+#![no_std]
+#![no_main]
+#![feature(panic_info_message)]
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use near_os::{exit_qemu, QemuExitCode, serial_print, serial_println};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("watchdog... ");
+
+    near_os::init();
+    near_os::watchdog::set_timeout(5);
+
+    // Hang with interrupts enabled and never touch the watchdog
+    loop {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+const EXPECTED: &str = "watchdog: kernel hung";
+
+// Keeps the start of the panic message
+struct Prefix {
+    buffer: [u8; 32],
+    len: usize,
+}
+
+impl Write for Prefix {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len < self.buffer.len() {
+                self.buffer[self.len] = byte;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The watchdog reports a hang by panicking, any other panic is a failure
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut prefix = Prefix { buffer: [0; 32], len: 0 };
+    if let Some(message) = info.message() {
+        let _ = prefix.write_fmt(*message);
+    }
+    if prefix.buffer[..prefix.len].starts_with(EXPECTED.as_bytes()) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("Error: {}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,98 @@
+#![no_std]
+#![no_main]
+
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use near_os::memory::{self, dump};
+use near_os::{serial_print, serial_println};
+use x86_64::registers::control::{Cr0, Cr0Flags, Efer, EferFlags};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// Address the test writes to, checked by the page fault handler
+static TARGET: AtomicU64 = AtomicU64::new(0);
+
+static READ_ONLY: &str = "read only data";
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("write_to_text_faults... ");
+
+    near_os::gdt::init();
+    init_test_idt();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    memory::protect_kernel(&mut mapper).expect("protect_kernel failed");
+    let nx = Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE);
+    assert_eq!(nx, memory::protect::nx_supported());
+    assert!(Cr0::read().contains(Cr0Flags::WRITE_PROTECT));
+
+    let text = main as usize as u64;
+    let rodata = READ_ONLY.as_ptr() as u64;
+    let data = &TARGET as *const AtomicU64 as u64;
+    let flags = memory::leaf_flags(VirtAddr::new(text));
+    assert!(!flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE));
+    let flags = memory::leaf_flags(VirtAddr::new(rodata));
+    assert!(!flags.contains(PageTableFlags::WRITABLE) && flags.contains(PageTableFlags::NO_EXECUTE));
+    let flags = memory::leaf_flags(VirtAddr::new(data));
+    assert!(flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+    // The alias of the code in the physical memory window
+    let phys = memory::translate(VirtAddr::new(text)).expect("code not mapped");
+    let alias = VirtAddr::new(phys.as_u64() + memory::physical_memory_offset());
+    let flags = memory::leaf_flags(alias);
+    assert!(flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+    let stack = &flags as *const PageTableFlags as u64;
+    assert!(memory::leaf_flags(VirtAddr::new(stack)).contains(PageTableFlags::NO_EXECUTE));
+
+    let violations = dump::check(memory::kernel_level_4_frame(), |violation| {
+        serial_println!("{:?}", violation);
+    });
+    assert_eq!(violations, 0);
+
+    TARGET.store(text, Ordering::SeqCst);
+    unsafe { (text as *mut u8).write_volatile(0xcc) };
+
+    panic!("Execution continued after writing to .text");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    near_os::test_panic_handler(info)
+}
+
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+use near_os::{exit_qemu, QemuExitCode};
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    error_code: PageFaultErrorCode) {
+    use x86_64::registers::control::Cr2;
+
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if Cr2::read().as_u64() == TARGET.load(Ordering::SeqCst) && error_code.contains(expected) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("Unexpected page fault at {:?}: {:?}", Cr2::read(), error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}